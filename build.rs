@@ -1,21 +1,82 @@
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 
+/// FLASH of the binaries running from the internal flash: the bootloader,
+/// and the demo application, which drives the octal flash directly.
+const INTERNAL_FLASH: &str = "
+MEMORY
+{
+    FLASH (xrw) : ORIGIN = 0x08000000, LENGTH = 64K /* User Flash: BANK1 */
+}
+";
+
+/// FLASH of the application executing in place: ACTIVE, after the image
+/// header slot and before a signature trailer (see src/image.rs).
+const ACTIVE_FLASH: &str = "
+MEMORY
+{
+    FLASH (rx) : ORIGIN = 0x70020400, LENGTH = 512K - 1K - 64
+}
+
+ASSERT(ORIGIN(FLASH) == ORIGIN(ACTIVE) + 1K, \"FLASH must start after the header slot\");
+ASSERT(ORIGIN(FLASH) + LENGTH(FLASH) <= ORIGIN(ACTIVE) + LENGTH(ACTIVE), \"FLASH must fit in ACTIVE\");
+";
+
+/// FLASH of the application executing in place from slot B of the A/B
+/// layout, with the `slot-b` feature (see src/ab.rs): the start of DFU, as
+/// large as ACTIVE.
+const SLOT_B_FLASH: &str = "
+MEMORY
+{
+    FLASH (rx) : ORIGIN = 0x700A0400, LENGTH = 512K - 1K - 64
+}
+
+ASSERT(ORIGIN(FLASH) == ORIGIN(DFU) + 1K, \"FLASH must start after the header slot\");
+ASSERT(ORIGIN(FLASH) + LENGTH(FLASH) <= ORIGIN(DFU) + LENGTH(ACTIVE), \"FLASH must fit in slot B\");
+";
+
 fn main() {
-    // Put `memory.x` in our output directory and ensure it's
-    // on the linker search path.
+    // Give each binary its own `memory.x` in our output directory, on its
+    // linker search path: the memory map shared by all binaries, plus the
+    // FLASH region the binary runs from.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    File::create(out.join("memory.x"))
-        .unwrap()
-        .write_all(include_bytes!("memory.x"))
-        .unwrap();
-    println!("cargo:rustc-link-search={}", out.display());
+    let app_flash = match env::var_os("CARGO_FEATURE_SLOT_B") {
+        Some(_) => SLOT_B_FLASH,
+        None => ACTIVE_FLASH,
+    };
+    for (bin, flash) in [
+        ("bootloader", INTERNAL_FLASH),
+        ("stm32h7s3l8-bootflash", INTERNAL_FLASH),
+        ("app", app_flash),
+    ] {
+        let dir = out.join(bin);
+        fs::create_dir_all(&dir).unwrap();
+        let mut memory = File::create(dir.join("memory.x")).unwrap();
+        memory.write_all(include_bytes!("memory.x")).unwrap();
+        memory.write_all(flash.as_bytes()).unwrap();
+        println!("cargo:rustc-link-arg-bin={bin}=-L{}", dir.display());
+    }
 
     // By default, Cargo will re-run a build script whenever
     // any file in the project changes. By specifying `memory.x`
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    // Compile in the public key checking image signatures, if given as a
+    // file holding the raw 32-byte Ed25519 key. All 0xFF means no key, like
+    // unprogrammed OTP.
+    println!("cargo:rerun-if-env-changed=BOOT_PUBLIC_KEY");
+    let key = match env::var_os("BOOT_PUBLIC_KEY") {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path.to_string_lossy());
+            let key = fs::read(&path).unwrap();
+            assert_eq!(key.len(), 32, "BOOT_PUBLIC_KEY must hold a raw 32-byte key");
+            key
+        }
+        None => vec![0xFF; 32],
+    };
+    fs::write(out.join("boot_public_key.bin"), key).unwrap();
 }
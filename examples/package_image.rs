@@ -0,0 +1,105 @@
+// Package an application as a firmware image for ACTIVE (see `image`): the
+// header slot, the payload and, given a signing key, the signature trailer
+// (see `signature`). Runs on the host:
+//
+//   cargo package-image [--slot a|b] <payload> <image> <image version>
+//       <security version> [<signing key>]
+//
+// The payload is the application as a flat binary, linked for ACTIVE (see
+// build.rs), e.g. from `cargo objcopy --release --bin app -- -O binary
+// app.bin`. The signing key is a file holding the raw 32-byte Ed25519 secret
+// key, whose public key the bootloader holds (see build.rs).
+//
+// With A/B slots (see `ab`), `--slot` selects the slot the image runs from:
+// a, the default, is ACTIVE, and b needs the payload linked for slot B, e.g.
+// with `--features slot-b`.
+
+use std::env;
+use std::fs;
+use std::process;
+
+use ed25519_dalek::{Signer, SigningKey};
+use stm32h7s3l8_bootflash::checksum::{CRC32_ISO_HDLC, Checksum, SoftwareCrc};
+use stm32h7s3l8_bootflash::digest::SoftwareSha256;
+use stm32h7s3l8_bootflash::image::{self, FLAG_TRAILER, HEADER_SLOT_SIZE, ImageHeader};
+use stm32h7s3l8_bootflash::signature::{self, SIGNATURE_LEN};
+
+/// ACTIVE (slot A) in the memory-mapped window, see memory.x.
+const SLOT_A_BASE: u32 = 0x7002_0000;
+
+/// Slot B in the memory-mapped window: the start of DFU, see memory.x.
+const SLOT_B_BASE: u32 = 0x700A_0000;
+
+const USAGE: &str = "Usage: package_image [--slot a|b] <payload> <image> <image version> \
+     <security version> [<signing key>]";
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    process::exit(2);
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let slot_base = match args.iter().position(|arg| arg == "--slot") {
+        Some(i) => {
+            let base = match args.get(i + 1).map(String::as_str) {
+                Some("a") => SLOT_A_BASE,
+                Some("b") => SLOT_B_BASE,
+                _ => usage(),
+            };
+            args.drain(i..i + 2);
+            base
+        }
+        None => SLOT_A_BASE,
+    };
+    if !(4..=5).contains(&args.len()) {
+        usage();
+    }
+    let payload = fs::read(&args[0]).expect("Cannot read the payload");
+    let image_version = args[2].parse().expect("Invalid image version");
+    let security_version = args[3].parse().expect("Invalid security version");
+    let key = args.get(4).map(|path| {
+        let key = fs::read(path).expect("Cannot read the signing key");
+        let key = key.try_into().expect("The signing key must hold 32 bytes");
+        SigningKey::from_bytes(&key)
+    });
+
+    // The entry point is the reset vector, the second entry of the vector
+    // table at the start of the payload.
+    let reset_vector = payload.get(4..8).expect("The payload is too short");
+    let entry_point = u32::from_le_bytes(reset_vector.try_into().unwrap());
+    let trailer_size = if key.is_some() { SIGNATURE_LEN } else { 0 };
+    let mut crc = SoftwareCrc::new(CRC32_ISO_HDLC);
+    crc.update(&payload);
+    let header = ImageHeader {
+        image_version,
+        load_address: slot_base + HEADER_SLOT_SIZE as u32,
+        image_size: payload.len() as u32,
+        entry_point,
+        flags: if key.is_some() { FLAG_TRAILER } else { 0 },
+        trailer_size: trailer_size as u32,
+        payload_crc: crc.finish(),
+        security_version,
+    };
+
+    let mut image = vec![0xFF; HEADER_SLOT_SIZE + payload.len() + trailer_size];
+    header.write(&mut image[..HEADER_SLOT_SIZE], &mut crc);
+    image[header.payload_range()].copy_from_slice(&payload);
+    if let Some(key) = &key {
+        let digest = signature::image_digest(&image, &header, &mut SoftwareSha256::new());
+        image[header.trailer_range()].copy_from_slice(&key.sign(&digest).to_bytes());
+    }
+
+    // Refuse to write an image the bootloader would refuse.
+    if let Err(e) = image::validate(&image, slot_base, &mut crc) {
+        eprintln!("Invalid image: {e:?}, is the payload linked for the slot?");
+        process::exit(1);
+    }
+    fs::write(&args[1], &image).expect("Cannot write the image");
+    println!(
+        "{}: version {image_version}, security version {security_version}, {} bytes, {}",
+        args[1],
+        image.len(),
+        if key.is_some() { "signed" } else { "unsigned" }
+    );
+}
@@ -26,8 +26,9 @@ MEMORY
       Note: 1 K = 1 KiBi = 1024 bytes
 
       TODO: properly assign the Octo-SPI flash regions.
+
+      FLASH, where the code goes, depends on the binary: see build.rs.
     */
-    FLASH           (xrw) : ORIGIN = 0x08000000, LENGTH =  64K /* User Flash: BANK1 */
     FLASH_SYSTEM     (rw) : ORIGIN = 0x1FF00000, LENGTH = 128K /* System Flash, exclusive for secure boot */
     FLASH_OTP        (rw) : ORIGIN = 0x08FF0000, LENGTH =   1K /* OTP Flash */
     BOOTLOADER_STATE (rw) : ORIGIN = 0x70000000, LENGTH = 128K /* Octo-SPI Flash on XSPI2 */
@@ -40,11 +41,45 @@ MEMORY
     DTCM            (xrw) : ORIGIN = 0x20000000, LENGTH =  64K /* Data Tightly-Coupled Memory*/
 }
 
-__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(FLASH);
-__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(FLASH);
+SECTIONS
+{
+  /* Position-independent test code, copied to the external flash at run time
+     to check execution in place (XIP) from the memory-mapped window. */
+  .xip_test : ALIGN(4)
+  {
+    __xip_test_start = .;
+    KEEP(*(.xip_test .xip_test.*));
+    . = ALIGN(4);
+    __xip_test_end = .;
+  } > FLASH
+} INSERT AFTER .text;
+
+SECTIONS
+{
+  /* Flash program/erase routines that must run while the external flash is
+     not memory-mapped. Loaded from FLASH, copied to ITCM by ram_flash::init().
+     The first bytes are skipped, to keep the code away from address 0. */
+  .itcm_text : ALIGN(4)
+  {
+    . += 16;
+    __sitcm_text = .;
+    KEEP(*(.itcm_text .itcm_text.*));
+    . = ALIGN(4);
+    __eitcm_text = .;
+  } > ITCM AT > FLASH
+
+  __sitcm_text_lma = LOADADDR(.itcm_text) + (__sitcm_text - ADDR(.itcm_text));
+} INSERT AFTER .xip_test;
+
+/* Partition offsets for the bootloader, relative to the start of the octal
+   flash, as used by the flash driver. The flash starts with BOOTLOADER_STATE. */
+__bootloader_flash_origin = ORIGIN(BOOTLOADER_STATE);
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - __bootloader_flash_origin;
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - __bootloader_flash_origin;
 
-__bootloader_active_start = ORIGIN(ACTIVE) - ORIGIN(FLASH);
-__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - ORIGIN(FLASH);
+__bootloader_active_start = ORIGIN(ACTIVE) - __bootloader_flash_origin;
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - __bootloader_flash_origin;
 
-__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(DFU);
-__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(DFU);
+__bootloader_dfu_start = ORIGIN(DFU) - __bootloader_flash_origin;
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - __bootloader_flash_origin;
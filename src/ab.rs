@@ -0,0 +1,252 @@
+// A/B slots, as an alternative to swapping (the `ab-slots` feature).
+//
+// Two slots hold a complete image each: slot A is the ACTIVE partition, and
+// slot B the start of DFU, as large as ACTIVE. Images are built for the slot
+// they run from (see the `slot-b` feature in build.rs), which their header
+// tells (see `image`), and executed in place from there. The application writes an update to the slot it does not
+// run from, and the bootloader boots the valid image with the highest
+// version, among the slots that may be booted.
+//
+// An update is booted on trial, as with swapping (see `swap`): if it is not
+// confirmed within `MAX_TRIAL_BOOTS` boots, or before the watchdog fires, it
+// is given up, and the other slot booted again.
+//
+// BOOTLOADER_STATE holds one sector of markers per slot, erased when an
+// update is written to the slot. After that, markers are only programmed,
+// each one once:
+//
+//   offset  marker
+//        0  writing an update
+//        2  update written, trial started
+//        4  update confirmed by the application
+//        6  slot rejected
+//      256  trial boots: one marker per boot, MAX_TRIAL_BOOTS of them
+//
+// A slot without any marker holds an image that was not installed as an
+// update, e.g. by a debug probe, and is treated as confirmed.
+
+use core::ops::Range;
+
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+
+use crate::boot;
+use crate::swap::{MARKER_SIZE, MAX_TRIAL_BOOTS};
+
+const MARKER_SET: [u8; MARKER_SIZE] = [0; MARKER_SIZE];
+
+/// Markers of each slot, in BOOTLOADER_STATE.
+const SLOT_STATE_SIZE: u32 = 4096;
+
+// Marker offsets in the markers of a slot.
+const WRITING: u32 = 0;
+const WRITTEN: u32 = 2;
+const CONFIRMED: u32 = 4;
+const REJECTED: u32 = 6;
+const TRIAL_BOOTS: u32 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub const ALL: [Slot; 2] = [Slot::A, Slot::B];
+
+    pub fn other(self) -> Self {
+        match self {
+            Self::A => Self::B,
+            Self::B => Self::A,
+        }
+    }
+
+    /// Bounds of the slot, as offsets in the flash.
+    pub fn partition(self) -> Range<usize> {
+        match self {
+            Self::A => boot::active_partition(),
+            Self::B => {
+                let start = boot::dfu_partition().start;
+                start..start + boot::active_partition().len()
+            }
+        }
+    }
+
+    /// Bounds of the slot, in the memory-mapped window.
+    pub fn range(self) -> Range<usize> {
+        boot::mapped_range(self.partition())
+    }
+
+    /// The slot the running application executes from.
+    pub fn running() -> Option<Self> {
+        // SAFETY: VTOR is only read.
+        let vtor = unsafe { (*cortex_m::peripheral::SCB::PTR).vtor.read() } as usize;
+        Self::ALL
+            .into_iter()
+            .find(|slot| slot.range().contains(&vtor))
+    }
+
+    /// Offset of the slot's markers in BOOTLOADER_STATE.
+    fn state_offset(self) -> u32 {
+        match self {
+            Self::A => 0,
+            Self::B => SLOT_STATE_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SlotState {
+    /// Not installed as an update: treated as confirmed.
+    Unmarked,
+    /// An update is being written.
+    Writing,
+    /// The update was booted `boots` times without confirmation.
+    Trial {
+        boots: usize,
+    },
+    Confirmed,
+    /// The update was not confirmed in time.
+    Rejected,
+}
+
+impl SlotState {
+    fn decode(markers: [bool; 4], boots: usize) -> Self {
+        let [writing, written, confirmed, rejected] = markers;
+        if rejected {
+            Self::Rejected
+        } else if confirmed {
+            Self::Confirmed
+        } else if written {
+            Self::Trial { boots }
+        } else if writing {
+            Self::Writing
+        } else {
+            Self::Unmarked
+        }
+    }
+
+    /// Whether an image in this state may be booted.
+    pub fn is_bootable(self) -> bool {
+        match self {
+            Self::Unmarked | Self::Confirmed => true,
+            Self::Trial { boots } => boots < MAX_TRIAL_BOOTS,
+            Self::Writing | Self::Rejected => false,
+        }
+    }
+}
+
+/// Pick the slot to boot: the valid image with the highest version, among
+/// the slots that may be booted, preferring a confirmed image to one on
+/// trial, then slot A. `versions` holds the version of the valid image in
+/// each slot, if any.
+pub fn select(states: [SlotState; 2], versions: [Option<u32>; 2]) -> Option<Slot> {
+    Slot::ALL
+        .into_iter()
+        .zip(states.into_iter().zip(versions))
+        .filter(|(_, (state, _))| state.is_bootable())
+        .filter_map(|(slot, (state, version))| {
+            let on_trial = matches!(state, SlotState::Trial { .. });
+            Some((slot, (version?, !on_trial, slot == Slot::A)))
+        })
+        .max_by_key(|&(_, preference)| preference)
+        .map(|(slot, _)| slot)
+}
+
+/// The slot markers in BOOTLOADER_STATE, as used by the bootloader.
+pub struct SlotStates<F> {
+    state: F,
+}
+
+impl<F: NorFlash> SlotStates<F> {
+    /// Panics if BOOTLOADER_STATE is too small, or the markers of the slots
+    /// do not fill whole sectors.
+    pub fn new(state: F) -> Self {
+        assert!(MARKER_SIZE % F::WRITE_SIZE == 0);
+        assert!(SLOT_STATE_SIZE as usize % F::ERASE_SIZE == 0);
+        assert!(state.capacity() >= 2 * SLOT_STATE_SIZE as usize);
+        Self { state }
+    }
+
+    fn is_set(&mut self, offset: u32) -> Result<bool, F::Error> {
+        let mut marker = [0; MARKER_SIZE];
+        self.state.read(offset, &mut marker)?;
+        Ok(marker == MARKER_SET)
+    }
+
+    pub fn get(&mut self, slot: Slot) -> Result<SlotState, F::Error> {
+        let base = slot.state_offset();
+        let mut markers = [false; 4];
+        for (marker, offset) in markers
+            .iter_mut()
+            .zip([WRITING, WRITTEN, CONFIRMED, REJECTED])
+        {
+            *marker = self.is_set(base + offset)?;
+        }
+        let mut boots = 0;
+        while boots < MAX_TRIAL_BOOTS
+            && self.is_set(base + TRIAL_BOOTS + (boots * MARKER_SIZE) as u32)?
+        {
+            boots += 1;
+        }
+        Ok(SlotState::decode(markers, boots))
+    }
+
+    /// Give up on the update in `slot`.
+    pub fn reject(&mut self, slot: Slot) -> Result<(), F::Error> {
+        self.state
+            .write(slot.state_offset() + REJECTED, &MARKER_SET)
+    }
+
+    /// Count a trial boot of `slot`, in state `Trial { boots }`.
+    pub fn count_boot(&mut self, slot: Slot, boots: usize) -> Result<(), F::Error> {
+        let offset = slot.state_offset() + TRIAL_BOOTS + (boots * MARKER_SIZE) as u32;
+        self.state.write(offset, &MARKER_SET)
+    }
+}
+
+async fn set_async<F: AsyncNorFlash>(
+    state: &mut F,
+    slot: Slot,
+    offset: u32,
+) -> Result<(), F::Error> {
+    state.write(slot.state_offset() + offset, &MARKER_SET).await
+}
+
+/// Start writing an update to `slot`, which must not be the running one.
+/// Called by the application, with BOOTLOADER_STATE, before erasing the
+/// slot.
+pub async fn begin_update<F: AsyncNorFlash>(state: &mut F, slot: Slot) -> Result<(), F::Error> {
+    assert_ne!(Some(slot), Slot::running());
+    let start = slot.state_offset();
+    state.erase(start, start + SLOT_STATE_SIZE).await?;
+    set_async(state, slot, WRITING).await
+}
+
+/// Have the update written to `slot` booted on trial, from the next boot.
+/// Called by the application, with BOOTLOADER_STATE.
+pub async fn finish_update<F: AsyncNorFlash>(state: &mut F, slot: Slot) -> Result<(), F::Error> {
+    set_async(state, slot, WRITTEN).await
+}
+
+/// Confirm the update running on trial, so it is kept. Called by the
+/// application, with BOOTLOADER_STATE, once it is known to work. Does
+/// nothing if not running from an update on trial.
+pub async fn mark_booted<F: AsyncNorFlash>(state: &mut F) -> Result<(), F::Error> {
+    let Some(slot) = Slot::running() else {
+        return Ok(());
+    };
+    let mut markers = [false; 4];
+    for (marker, offset) in markers
+        .iter_mut()
+        .zip([WRITING, WRITTEN, CONFIRMED, REJECTED])
+    {
+        let mut bytes = [0; MARKER_SIZE];
+        state.read(slot.state_offset() + offset, &mut bytes).await?;
+        *marker = bytes == MARKER_SET;
+    }
+    if let SlotState::Trial { .. } = SlotState::decode(markers, 0) {
+        set_async(state, slot, CONFIRMED).await?;
+    }
+    Ok(())
+}
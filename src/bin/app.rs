@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+
+//! Minimal application for the bootloader, executing in place from ACTIVE.
+//!
+//! It is linked for ACTIVE, after the image header slot (see build.rs), and
+//! packaged as an image by `cargo package-image` (see
+//! examples/package_image.rs). It follows the bootloader-to-application
+//! contract (see `boot`): the clocks and the memory-mapped flash stay as the
+//! bootloader left them, so `embassy_stm32::init()` is not called.
+//!
+//! With the `ab-slots` feature, an image runs from the slot it is linked
+//! for: slot A (ACTIVE), or slot B with the `slot-b` feature. Updates are
+//! built for the slot the running image does not occupy, and packaged with
+//! `--slot`.
+
+use core::ops::Range;
+
+use cortex_m::asm;
+use cortex_m_rt::entry;
+use defmt::info;
+use embassy_embedded_hal::adapter::BlockingAsync;
+use embassy_embedded_hal::flash::partition::Partition;
+use embassy_futures::block_on;
+use embassy_stm32::pac;
+use embassy_stm32::pac::iwdg::vals::Key;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+#[cfg(feature = "ab-slots")]
+use stm32h7s3l8_bootflash::ab;
+use stm32h7s3l8_bootflash::boot;
+use stm32h7s3l8_bootflash::ram_flash::{self, XipFlash};
+#[cfg(not(feature = "ab-slots"))]
+use stm32h7s3l8_bootflash::swap::FirmwareUpdater;
+use {defmt_rtt as _, panic_probe as _};
+
+/// SYSCLK, as set up by the bootloader (see `board::config()`).
+const SYSCLK_HZ: u32 = 600_000_000;
+
+#[entry]
+fn main() -> ! {
+    info!("Application started");
+
+    // The bootloader leaves the caches disabled.
+    let mut cor = cortex_m::Peripherals::take().unwrap();
+    cor.SCB.enable_icache();
+    cor.SCB.enable_dcache(&mut cor.CPUID);
+
+    // Load the flash routines used while executing in place.
+    ram_flash::init();
+
+    let flash = Mutex::<NoopRawMutex, _>::new(BlockingAsync::new(XipFlash));
+    let partition =
+        |range: Range<usize>| Partition::new(&flash, range.start as u32, range.len() as u32);
+    block_on(async {
+        #[cfg(not(feature = "ab-slots"))]
+        {
+            let mut updater = FirmwareUpdater::new(
+                partition(boot::dfu_partition()),
+                partition(boot::state_partition()),
+            );
+            let update_state = updater.get_state().await.unwrap();
+            info!("Firmware update state: {}", update_state);
+            // Started: keep this image, if it runs on trial.
+            updater.mark_booted().await.unwrap();
+        }
+        #[cfg(feature = "ab-slots")]
+        {
+            info!("Running from slot {}", ab::Slot::running());
+            // Started: keep this image, if it runs on trial.
+            let mut state_partition = partition(boot::state_partition());
+            ab::mark_booted(&mut state_partition).await.unwrap();
+        }
+    });
+
+    loop {
+        // After a trial boot, the watchdog keeps running. Reloading it does
+        // nothing if it was not started.
+        pac::IWDG.kr().write(|w| w.set_key(Key::RESET));
+        asm::delay(SYSCLK_HZ);
+    }
+}
@@ -0,0 +1,336 @@
+#![no_std]
+#![no_main]
+
+//! Bootloader for the Nucleo STM32H7S3L8 MB1737.
+//!
+//! Runs from the internal flash. Completes pending firmware updates by
+//! swapping the ACTIVE and DFU partitions of the octal flash (see `swap`),
+//! then starts the application in ACTIVE, executing in place. With the
+//! `ab-slots` feature, starts the newest image of two slots instead, in
+//! place, without swapping (see `ab`).
+//!
+//! Only intact images signed with the bootloader's key (see `signature`) are
+//! swapped in or started, and none older than the security counter (see
+//! `rollback`). An update is started on trial, with the independent watchdog
+//! running, until the application confirms it. The security counter is then
+//! raised to the update's security version.
+//!
+//! The application is linked for ACTIVE (see build.rs and `src/bin/app.rs`),
+//! and packaged as a signed image by `cargo package-image`. Updates are
+//! written to DFU with `swap::FirmwareUpdater`. With A/B slots, an update is
+//! linked and packaged for the slot it is written to (`slot-b` feature and
+//! `--slot` option).
+
+use core::cell::RefCell;
+use core::ops::Range;
+
+use cortex_m_rt::entry;
+use defmt::{info, panic, warn};
+use ed25519_dalek::VerifyingKey;
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_stm32::wdg::IndependentWatchdog;
+use embassy_stm32::xspi::Xspi;
+use embassy_stm32::{bind_interrupts, hash, pac, peripherals};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_time::{Duration, block_for};
+#[cfg(feature = "ab-slots")]
+use stm32h7s3l8_bootflash::ab::{self, Slot, SlotState, SlotStates};
+use stm32h7s3l8_bootflash::board::{self, XSPI_CLOCKS};
+use stm32h7s3l8_bootflash::checksum::{CRC32_ISO_HDLC, Checksum, HardwareCrc};
+use stm32h7s3l8_bootflash::digest::{Digest, HardwareSha256};
+use stm32h7s3l8_bootflash::image::{ImageError, ImageHeader};
+use stm32h7s3l8_bootflash::mapped::MemoryMap;
+#[cfg(not(feature = "ab-slots"))]
+use stm32h7s3l8_bootflash::mx25uw25645g::MEMORY_SECTOR_SIZE;
+use stm32h7s3l8_bootflash::mx25uw25645g::OpiFlashMemory;
+use stm32h7s3l8_bootflash::rollback::{self, RollbackError};
+use stm32h7s3l8_bootflash::signature::{self, SignatureError};
+use stm32h7s3l8_bootflash::swap::MAX_TRIAL_BOOTS;
+#[cfg(not(feature = "ab-slots"))]
+use stm32h7s3l8_bootflash::swap::{Partitions, State};
+use stm32h7s3l8_bootflash::{boot, image, mpu};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    HASH => hash::InterruptHandler<peripherals::HASH>;
+});
+
+/// Why an image is not swapped in or started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum Rejected {
+    Image(ImageError),
+    Signature(SignatureError),
+    Rollback(RollbackError),
+}
+
+type Flash = Mutex<NoopRawMutex, RefCell<OpiFlashMemory>>;
+
+/// The image to start.
+struct Selected {
+    /// Partition holding the image, as offsets in the flash.
+    partition: Range<usize>,
+    header: ImageHeader,
+    /// Boots of the image so far, including this one, if booted on trial.
+    trial_boots: Option<usize>,
+    /// The image is a confirmed update.
+    confirmed: bool,
+}
+
+fn partition(
+    flash: &Flash,
+    range: Range<usize>,
+) -> BlockingPartition<'_, NoopRawMutex, OpiFlashMemory> {
+    BlockingPartition::new(flash, range.start as u32, range.len() as u32)
+}
+
+/// Validate and check the signature of the image in `partition`, which will
+/// execute from `mapped_base`, and that it is not older than `counter`, the
+/// security counter.
+fn check_image(
+    partition: &[u8],
+    mapped_base: usize,
+    counter: u32,
+    crc: &mut impl Checksum,
+    key: &VerifyingKey,
+    digest: &mut impl Digest,
+) -> Result<ImageHeader, Rejected> {
+    let header = image::validate(partition, mapped_base as u32, crc).map_err(Rejected::Image)?;
+    rollback::check(&header, counter).map_err(Rejected::Rollback)?;
+    signature::verify_image(partition, &header, key, digest).map_err(Rejected::Signature)?;
+    Ok(header)
+}
+
+/// Check the image at `range`, given as offsets in the flash, in place.
+fn check_partition(
+    flash: &Flash,
+    range: Range<usize>,
+    counter: u32,
+    crc: &mut impl Checksum,
+    key: &VerifyingKey,
+    digest: &mut impl Digest,
+) -> Result<ImageHeader, Rejected> {
+    flash.lock(|flash| {
+        let mut flash = flash.borrow_mut();
+        let mapped = flash.map();
+        let mapped_base = boot::mapped_range(range.clone()).start;
+        check_image(&mapped[range], mapped_base, counter, crc, key, digest)
+    })
+}
+
+/// Complete a pending swap or revert, and select the image in ACTIVE.
+#[cfg(not(feature = "ab-slots"))]
+fn select_image(
+    flash: &Flash,
+    watchdog_reset: bool,
+    counter: u32,
+    crc: &mut impl Checksum,
+    key: &VerifyingKey,
+    digest: &mut impl Digest,
+) -> Selected {
+    let mut partitions = Partitions::new(
+        partition(flash, boot::active_partition()),
+        partition(flash, boot::dfu_partition()),
+        partition(flash, boot::state_partition()),
+    );
+
+    // Refuse to swap in an update that is not intact and signed, or older
+    // than the security counter: cancel it, keeping the current image. Once
+    // started, a swap must complete, as DFU no longer holds the whole update.
+    if partitions.state().unwrap() == (State::Swap { steps_done: 0 }) {
+        // Only the part that fits in ACTIVE is swapped in, and executes from
+        // there.
+        let dfu = boot::dfu_partition();
+        let len = dfu.len().min(boot::active_partition().len());
+        let checked = flash.lock(|flash| {
+            let mut flash = flash.borrow_mut();
+            let mapped = flash.map();
+            let update = &mapped[dfu.start..dfu.start + len];
+            check_image(
+                update,
+                boot::active_range().start,
+                counter,
+                crc,
+                key,
+                digest,
+            )
+        });
+        if let Err(e) = checked {
+            warn!("Cancelling update, invalid image in DFU: {}", e);
+            partitions.cancel().unwrap();
+        }
+    }
+
+    let mut sector_buf = [0; MEMORY_SECTOR_SIZE];
+    let mut state = partitions
+        .prepare_boot(&mut sector_buf, watchdog_reset)
+        .unwrap();
+
+    // Refuse to start anything but a complete, intact, signed image. An
+    // update on trial that is refused is reverted, as the previous image is
+    // still in DFU.
+    let active = boot::active_partition();
+    let header = loop {
+        info!("Boot state: {}", state);
+        match check_partition(flash, active.clone(), counter, crc, key, digest) {
+            Ok(header) => break header,
+            Err(e) if matches!(state, State::Trial { .. }) => {
+                warn!("Reverting update, invalid image in ACTIVE: {}", e);
+                partitions.revert().unwrap();
+                state = partitions.prepare_boot(&mut sector_buf, false).unwrap();
+            }
+            Err(e) => panic!("Invalid image in ACTIVE: {}", e),
+        }
+    };
+    let trial_boots = match state {
+        State::Trial { boots } => Some(boots),
+        _ => None,
+    };
+    Selected {
+        partition: active,
+        header,
+        trial_boots,
+        confirmed: state == State::Confirmed,
+    }
+}
+
+/// Select the slot to boot, rejecting an update that was not confirmed in
+/// time.
+#[cfg(feature = "ab-slots")]
+fn select_image(
+    flash: &Flash,
+    watchdog_reset: bool,
+    counter: u32,
+    crc: &mut impl Checksum,
+    key: &VerifyingKey,
+    digest: &mut impl Digest,
+) -> Selected {
+    let mut slot_states = SlotStates::new(partition(flash, boot::state_partition()));
+    let mut states = Slot::ALL.map(|slot| slot_states.get(slot).unwrap());
+    info!("Slot states: {}", states);
+
+    // Refuse to start anything but a complete, intact, signed image.
+    let headers = Slot::ALL.map(|slot| {
+        if !states[slot as usize].is_bootable() {
+            return None;
+        }
+        match check_partition(flash, slot.partition(), counter, crc, key, digest) {
+            Ok(header) => Some(header),
+            Err(e) => {
+                warn!("Invalid image in slot {}: {}", slot, e);
+                None
+            }
+        }
+    });
+    let versions = headers.map(|header| header.map(|header| header.image_version));
+
+    // The slot selected before a watchdog reset is the one that was running
+    // when it fired: reject it if on trial, and fall back to the other one.
+    let mut watchdog_reset = watchdog_reset;
+    let slot = loop {
+        let Some(slot) = ab::select(states, versions) else {
+            panic!("No valid image in either slot");
+        };
+        match states[slot as usize] {
+            SlotState::Trial { boots } if watchdog_reset && boots > 0 => {
+                warn!("Rejecting update in slot {}, reset by the watchdog", slot);
+                slot_states.reject(slot).unwrap();
+                states[slot as usize] = SlotState::Rejected;
+                watchdog_reset = false;
+            }
+            _ => break slot,
+        }
+    };
+
+    let trial_boots = match states[slot as usize] {
+        SlotState::Trial { boots } => {
+            slot_states.count_boot(slot, boots).unwrap();
+            Some(boots + 1)
+        }
+        _ => None,
+    };
+    info!("Booting slot {}", slot);
+    Selected {
+        partition: slot.partition(),
+        header: headers[slot as usize].unwrap(),
+        trial_boots,
+        confirmed: states[slot as usize] == SlotState::Confirmed,
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    // A watchdog reset during a trial boot reverts the update.
+    let watchdog_reset = pac::RCC.rsr().read().iwdgrstf();
+    pac::RCC.rsr().modify(|w| w.set_rmvf(true));
+
+    let p = embassy_stm32::init(board::config());
+
+    // Keep the CPU away from the XSPI2 window while it is not memory-mapped.
+    mpu::init();
+
+    let xspi = Xspi::new_blocking_xspi_dqs(
+        p.XSPI2,
+        p.PN6,
+        p.PN2,
+        p.PN3,
+        p.PN4,
+        p.PN5,
+        p.PN8,
+        p.PN9,
+        p.PN10,
+        p.PN11,
+        p.PN1,
+        p.PN0,
+        board::xspi_config(),
+    );
+    // Give the flash time to recover from reset, see main.rs.
+    block_for(Duration::from_micros(50));
+    let mut flash = OpiFlashMemory::new(xspi);
+    flash.set_clock_prescaler(XSPI_CLOCKS.opi_prescaler);
+
+    let key = match signature::public_key() {
+        Ok(key) => key,
+        Err(e) => panic!("No public key to check images: {}", e),
+    };
+    let mut crc = HardwareCrc::new(p.CRC, CRC32_ISO_HDLC);
+    let mut sha = HardwareSha256::new(p.HASH, p.GPDMA1_CH0, Irqs);
+
+    let counter = rollback::read_counter(&mut flash);
+    info!("Security counter: {}", counter);
+
+    let flash = Mutex::<NoopRawMutex, _>::new(RefCell::new(flash));
+    let selected = select_image(&flash, watchdog_reset, counter, &mut crc, &key, &mut sha);
+    let header = selected.header;
+    info!(
+        "Image version {:#010x}, security version {}, {} bytes",
+        header.image_version, header.security_version, header.image_size
+    );
+    let mut flash = flash.into_inner().into_inner();
+
+    // Only once confirmed may an update keep older images from being
+    // installed again.
+    if selected.confirmed && header.security_version > counter {
+        match rollback::raise_counter(&mut flash, header.security_version) {
+            Ok(raised) if raised == header.security_version => {
+                info!("Security counter raised to {}", raised)
+            }
+            Ok(raised) => warn!("Security counter only raised to {}", raised),
+            Err(e) => warn!("Security counter not raised: {}", e),
+        }
+    }
+
+    // The application must confirm the update, or keep the watchdog from
+    // firing until it does.
+    if let Some(boots) = selected.trial_boots {
+        info!("Trial boot {} of {}", boots, MAX_TRIAL_BOOTS);
+        IndependentWatchdog::new(p.IWDG, boot::TRIAL_WATCHDOG_TIMEOUT_US).unleash();
+    }
+
+    flash.enable_mm();
+    info!("Starting application at {:#010x}", header.load_address);
+    let executable = boot::mapped_range(selected.partition);
+    // SAFETY: the flash is memory-mapped, and the bootloader is done.
+    let Err(e) = (unsafe { boot::jump_to_application(header.load_address as usize, executable) });
+    panic!("No valid application: {}", e);
+}
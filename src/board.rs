@@ -0,0 +1,104 @@
+// Clock and XSPI settings of the Nucleo board, shared by the application and
+// the bootloader.
+
+use embassy_stm32::Config;
+use embassy_stm32::pac::rcc::vals::Plldivst; // TODO: not exported in embassy-stm32/src/rcc/h.rs.
+use embassy_stm32::rcc::{
+    AHBPrescaler, APBPrescaler, Hse, HseMode, Pll, PllDiv, PllMul, PllPreDiv, PllSource, Sysclk,
+    VoltageScale, mux::Xspisel,
+};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::xspi::{
+    ChipSelectHighTime, FIFOThresholdLevel, MemorySize, MemoryType, WrapSize,
+};
+
+use crate::xspi_clocks::{self, XspiClocks};
+
+/// HSE crystal frequency on the Nucleo board.
+pub const HSE_FREQ: Hertz = Hertz(24_000_000);
+
+/// Flash bus clock while the flash is in SPI mode.
+pub const XSPI_SPI_BUS_FREQ: Hertz = Hertz(75_000_000);
+
+/// Flash bus clock while the flash is in OPI mode.
+/// TODO: 200 MHz should be possible, but the first errors appear at 150 MHz.
+pub const XSPI_OPI_BUS_FREQ: Hertz = Hertz(150_000_000);
+
+/// PLL2 and XSPI prescaler settings for the above bus clocks.
+pub const XSPI_CLOCKS: XspiClocks =
+    match xspi_clocks::xspi_clocks(HSE_FREQ.0, XSPI_SPI_BUS_FREQ.0, XSPI_OPI_BUS_FREQ.0) {
+        Some(clocks) => clocks,
+        None => panic!("No valid PLL2/XSPI clock configuration for the requested bus clocks"),
+    };
+
+/// HAL configuration: 600 MHz SYSCLK from PLL1, XSPI2 kernel clock from PLL2_S.
+pub fn config() -> Config {
+    let mut config = Config::default();
+    config.rcc.hse = Some(Hse {
+        freq: HSE_FREQ,
+        mode: HseMode::Oscillator,
+    });
+    config.rcc.pll1 = Some(Pll {
+        source: PllSource::HSE,
+        prediv: PllPreDiv::DIV2,
+        mul: PllMul::MUL50,       // 600 MHz.
+        divp: Some(PllDiv::DIV1), // 600 MHz PLLCLK for SYSCLK.
+        divq: None,
+        divr: None,
+        divs: None,
+        divt: None,
+    });
+    // TODO: pll2 is not fully implemented yet: s,t channels are TODO in
+    // "embassy-stm32/src/rcc/h.rs" L735,737. Also, the "Plldivst" type is
+    // not properly exported. The RCC Clocks debug info shows zeros, but
+    // the PLL does generate the expected clocks.
+    // <https://github.com/embassy-rs/embassy/blob/main/embassy-stm32/src/rcc/h.rs>
+    // L735, L737
+    //
+    // Using PLL2_S as the clock source for the XSPI gives more flexibility
+    // in clock rates. The PLL2 settings are calculated at compile time,
+    // see the xspi_clocks module.
+    config.rcc.pll2 = Some(Pll {
+        source: PllSource::HSE,
+        prediv: PllPreDiv::from_bits(XSPI_CLOCKS.prediv),
+        mul: PllMul::from_bits(XSPI_CLOCKS.mul - 1),
+        divp: Some(PllDiv::DIV1), // For debug: p,q,p,r are included in rcc Clocks log.
+        divq: None,
+        divr: None,
+        divs: Some(Plldivst::from_bits(XSPI_CLOCKS.divs - 1)),
+        divt: None,
+    });
+    config.rcc.sys = Sysclk::PLL1_P; // 600 MHz.
+    config.rcc.ahb_pre = AHBPrescaler::DIV2; // 300 MHz.
+    config.rcc.apb1_pre = APBPrescaler::DIV2; // 150 MHz.
+    config.rcc.apb2_pre = APBPrescaler::DIV2; // 150 MHz.
+    config.rcc.apb4_pre = APBPrescaler::DIV2; // 150 MHz.
+    config.rcc.apb5_pre = APBPrescaler::DIV2; // 150 MHz.
+    config.rcc.voltage_scale = VoltageScale::HIGH;
+    config.rcc.mux.xspi2sel = Xspisel::PLL2_S; // XSPI2 uses PLL2_S.
+    config
+}
+
+/// XSPI2 configuration for the flash, right after reset (SPI mode).
+pub fn xspi_config() -> embassy_stm32::xspi::Config {
+    embassy_stm32::xspi::Config {
+        fifo_threshold: FIFOThresholdLevel::_4Bytes,
+        memory_type: MemoryType::Macronix,
+        delay_hold_quarter_cycle: true,
+        device_size: MemorySize::_32MiB,
+        chip_select_high_time: ChipSelectHighTime::_2Cycle,
+        free_running_clock: false,
+        clock_mode: false,
+        wrap_size: WrapSize::None,
+        // The initial bus clock is set for SPI mode, as the flash will be in
+        // SPI mode after reset. This is above the 50 MHz max for SPI READ
+        // instructions, so the FAST READ instruction must be used. The nucleo
+        // board's flash can run at up to 133 MHz in SPI mode and 200 MHz in
+        // OPI mode.
+        clock_prescaler: XSPI_CLOCKS.spi_prescaler,
+        sample_shifting: false,
+        chip_select_boundary: 0,
+        max_transfer: 0,
+        refresh: 0,
+    }
+}
@@ -0,0 +1,194 @@
+// Starting the application from the bootloader.
+//
+// The application executes in place from the ACTIVE partition of the octal
+// flash, through the XSPI2 memory-mapped window, or from either slot with the
+// `ab-slots` feature (see `ab`). The partition holds an image (see `image`):
+// the application's vector table starts the payload, right after the header
+// slot. build.rs links `src/bin/app.rs` there.
+//
+// Bootloader-to-application contract, for applications started by the
+// bootloader, such as `src/bin/app.rs`. The demo application (src/main.rs) is
+// not one: it runs from the internal flash in place of the bootloader, sets
+// up the clocks itself, and drives the octal flash directly. When the
+// application's reset handler runs:
+//
+// - Clocks are as set up by `board::config()`: SYSCLK 600 MHz from PLL1,
+//   AHB 300 MHz, APB1..5 150 MHz, XSPI2 kernel clock from PLL2_S (see
+//   `board::XSPI_CLOCKS`). The application must not stop or reprogram PLL2,
+//   nor change the XSPI2 kernel clock mux, while executing from the flash.
+//   Note that `embassy_stm32::init()` reprograms all PLLs, so it must not be
+//   called from code in the flash. The application uses the PAC instead, or
+//   drivers that do not need it, such as `ram_flash`.
+// - XSPI2 is in memory-mapped mode, with the flash in octal DTR mode, 20
+//   dummy cycles, DQS enabled, the OPI clock prescaler and prefetching on.
+//   To program or erase the flash, leave memory-mapped mode from code that
+//   does not run from the flash, e.g. with `ram_flash`.
+// - The MPU is enabled, with the 32 MiB flash readable and executable, and
+//   the rest of the XSPI2 window inaccessible (see `mpu`). PRIVDEFENA is set.
+// - Interrupts are enabled (PRIMASK cleared), but every interrupt is disabled
+//   and unpended in the NVIC. SysTick is stopped.
+// - The I-cache and D-cache are disabled. `SCB::enable_icache()` and
+//   `SCB::enable_dcache()` invalidate them before enabling them again.
+// - VTOR points to the application's vector table, and MSP holds its initial
+//   stack pointer.
+// - After an update, until the application confirms it with
+//   `swap::mark_booted()`, or `ab::mark_booted()` with A/B slots, it is
+//   booted on trial: the independent watchdog runs, with a
+//   `TRIAL_WATCHDOG_TIMEOUT_US` timeout. It cannot be stopped, so the
+//   application must keep feeding it, even once confirmed.
+// - Other peripherals, such as the TIM2 time driver and the GPIOs used by
+//   the bootloader, are left as they are, and should be reinitialized.
+
+use core::convert::Infallible;
+use core::ops::Range;
+use core::ptr;
+
+use cortex_m::{asm, interrupt};
+
+use crate::mapped::XSPI2_MAPPED_BASE;
+
+// Partition offsets in the octal flash, see memory.x.
+unsafe extern "C" {
+    static __bootloader_state_start: u32;
+    static __bootloader_state_end: u32;
+    static __bootloader_active_start: u32;
+    static __bootloader_active_end: u32;
+    static __bootloader_dfu_start: u32;
+    static __bootloader_dfu_end: u32;
+}
+
+/// Independent watchdog timeout during trial boots.
+pub const TRIAL_WATCHDOG_TIMEOUT_US: u32 = 10_000_000;
+
+/// RAM that may hold the application's initial stack: AXI SRAM and DTCM,
+/// see memory.x. The stack pointer may point right past the end.
+const STACK_RANGES: [Range<u32>; 2] = [0x2400_0000..0x2407_2000, 0x2000_0000..0x2001_0000];
+
+/// VTOR alignment: the vector table (16 + 155 entries) rounded up to a power
+/// of two.
+const VECTOR_TABLE_ALIGN: usize = 1024;
+
+/// SCB ICSR bits to clear pending SysTick and PendSV exceptions.
+const ICSR_PENDSTCLR: u32 = 1 << 25;
+const ICSR_PENDSVCLR: u32 = 1 << 27;
+
+/// Why a vector table was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum VectorTableError {
+    /// The table is not aligned as VTOR requires.
+    Unaligned,
+    /// The initial stack pointer is not 8-byte aligned, or not in RAM.
+    StackPointer(u32),
+    /// The reset handler is not a Thumb address inside the image's
+    /// partition.
+    ResetHandler(u32),
+}
+
+/// Bounds of BOOTLOADER_STATE, as offsets in the flash.
+pub fn state_partition() -> Range<usize> {
+    let start = &raw const __bootloader_state_start as usize;
+    let end = &raw const __bootloader_state_end as usize;
+    start..end
+}
+
+/// Bounds of ACTIVE, as offsets in the flash.
+pub fn active_partition() -> Range<usize> {
+    let start = &raw const __bootloader_active_start as usize;
+    let end = &raw const __bootloader_active_end as usize;
+    start..end
+}
+
+/// Bounds of DFU, as offsets in the flash.
+pub fn dfu_partition() -> Range<usize> {
+    let start = &raw const __bootloader_dfu_start as usize;
+    let end = &raw const __bootloader_dfu_end as usize;
+    start..end
+}
+
+/// Bounds of `partition`, given as offsets in the flash, in the
+/// memory-mapped window.
+pub fn mapped_range(partition: Range<usize>) -> Range<usize> {
+    XSPI2_MAPPED_BASE + partition.start..XSPI2_MAPPED_BASE + partition.end
+}
+
+/// Bounds of ACTIVE, in the memory-mapped window.
+pub fn active_range() -> Range<usize> {
+    mapped_range(active_partition())
+}
+
+/// Check the first two entries of a vector table at `vector_table`: the
+/// initial stack pointer `sp` and the reset handler `reset`, which must be
+/// in `executable`, the image's partition in the memory-mapped window.
+pub fn check_vector_table(
+    vector_table: usize,
+    sp: u32,
+    reset: u32,
+    executable: &Range<usize>,
+) -> Result<(), VectorTableError> {
+    if vector_table % VECTOR_TABLE_ALIGN != 0 {
+        return Err(VectorTableError::Unaligned);
+    }
+
+    let sp_in_ram = STACK_RANGES
+        .iter()
+        .any(|ram| ram.start < sp && sp <= ram.end);
+    if sp % 8 != 0 || !sp_in_ram {
+        return Err(VectorTableError::StackPointer(sp));
+    }
+
+    let handler = (reset & !1) as usize;
+    if reset & 1 == 0 || !executable.contains(&handler) {
+        return Err(VectorTableError::ResetHandler(reset));
+    }
+    Ok(())
+}
+
+/// Start the application whose vector table is at `vector_table`, in the
+/// partition at `executable` in the memory-mapped window, leaving the CPU in
+/// the state described at the top of this file. Returns only if the vector
+/// table is invalid.
+///
+/// # Safety
+///
+/// The flash must be memory-mapped, and nothing may rely on the caches,
+/// interrupts or SysTick of the caller anymore.
+pub unsafe fn jump_to_application(
+    vector_table: usize,
+    executable: Range<usize>,
+) -> Result<Infallible, VectorTableError> {
+    let table = vector_table as *const u32;
+    let (sp, reset) = unsafe { (ptr::read_volatile(table), ptr::read_volatile(table.add(1))) };
+    check_vector_table(vector_table, sp, reset, &executable)?;
+
+    interrupt::disable();
+
+    // SAFETY: this is the last code of the bootloader to run.
+    let mut p = unsafe { cortex_m::Peripherals::steal() };
+
+    p.SYST.disable_interrupt();
+    p.SYST.disable_counter();
+    p.SYST.clear_current();
+
+    unsafe {
+        for (icer, icpr) in p.NVIC.icer.iter().zip(p.NVIC.icpr.iter()) {
+            icer.write(0xFFFF_FFFF);
+            icpr.write(0xFFFF_FFFF);
+        }
+        p.SCB.icsr.write(ICSR_PENDSTCLR | ICSR_PENDSVCLR);
+    }
+
+    // Cleans the D-cache first, so nothing written by the bootloader is lost.
+    p.SCB.disable_dcache(&mut p.CPUID);
+    p.SCB.disable_icache();
+
+    unsafe { p.SCB.vtor.write(vector_table as u32) };
+    asm::dsb();
+    asm::isb();
+
+    // Nothing can fire anymore, and the application expects the reset
+    // value of PRIMASK.
+    unsafe {
+        interrupt::enable();
+        asm::bootload(table)
+    }
+}
@@ -0,0 +1,60 @@
+// CPU cache maintenance for the memory-mapped flash window.
+//
+// Reads through the memory-mapped window are cached like any other memory.
+// After the flash is programmed or erased through indirect commands, the
+// D-cache and I-cache may still hold the old contents, so the affected lines
+// must be invalidated before the window is read (or executed from) again.
+
+use cortex_m::asm;
+
+/// Cortex-M7 cache line size.
+const CACHE_LINE_SIZE: usize = 32;
+
+/// Above this size, maintaining the whole cache is faster than going line by
+/// line. This is the size of the (largest) L1 cache.
+const WHOLE_CACHE_THRESHOLD: usize = 32 * 1024;
+
+/// How the flash driver keeps the caches coherent after program and erase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum CacheCoherency {
+    /// Invalidate the affected D-cache and I-cache lines.
+    Invalidate,
+    /// Clean the affected D-cache lines before invalidating them, in case
+    /// the window has been written through.
+    CleanInvalidate,
+    /// Do nothing: the caller manages coherency.
+    Manual,
+}
+
+/// Make the caches coherent with the memory at `addr..addr + len`.
+pub fn sync_range(addr: usize, len: usize, mode: CacheCoherency) {
+    if mode == CacheCoherency::Manual || len == 0 {
+        return;
+    }
+
+    // SAFETY: cache maintenance does not conflict with other users of these
+    // peripherals, as long as RAM contents are never discarded (see below).
+    let mut p = unsafe { cortex_m::Peripherals::steal() };
+
+    if len > WHOLE_CACHE_THRESHOLD {
+        // Invalidating the whole D-cache without cleaning would throw away
+        // dirty RAM data, so always clean it first.
+        p.SCB.clean_invalidate_dcache(&mut p.CPUID);
+        p.SCB.invalidate_icache();
+        return;
+    }
+
+    let start = addr & !(CACHE_LINE_SIZE - 1);
+    let end = addr + len;
+    asm::dsb();
+    for line in (start..end).step_by(CACHE_LINE_SIZE) {
+        match mode {
+            CacheCoherency::CleanInvalidate => p.CBP.dccimvac(line as u32),
+            _ => p.CBP.dcimvac(line as u32),
+        }
+        p.CBP.icimvau(line as u32);
+    }
+    p.CBP.bpiall();
+    asm::dsb();
+    asm::isb();
+}
@@ -0,0 +1,186 @@
+// CRC-32 checksums over flash contents, for image validation and self-test.
+//
+// The same algorithm is available in two flavours: one using the STM32 CRC
+// peripheral, and a bitwise software implementation that runs anywhere
+// (including on the host) and gives identical results for identical
+// parameters. `HardwareCrc` is only built for the target.
+
+#[cfg(target_os = "none")]
+use embassy_stm32::Peri;
+#[cfg(target_os = "none")]
+use embassy_stm32::crc::{Config, Crc, InputReverseConfig, PolySize};
+#[cfg(target_os = "none")]
+use embassy_stm32::peripherals::CRC;
+
+/// Parameters of a 32-bit CRC, using the usual "Rocksoft" model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub struct CrcParams {
+    /// Generator polynomial, in normal (MSB-first) notation.
+    pub poly: u32,
+    /// Initial value of the CRC register.
+    pub init: u32,
+    /// Reflect each input byte before processing it.
+    pub reflect_in: bool,
+    /// Reflect the CRC register before the final XOR.
+    pub reflect_out: bool,
+    /// Value XOR-ed with the result.
+    pub xor_out: u32,
+}
+
+/// The common CRC-32 (Ethernet, zip, PNG), check value 0xCBF43926.
+pub const CRC32_ISO_HDLC: CrcParams = CrcParams {
+    poly: 0x04C1_1DB7,
+    init: 0xFFFF_FFFF,
+    reflect_in: true,
+    reflect_out: true,
+    xor_out: 0xFFFF_FFFF,
+};
+
+/// CRC-32/MPEG-2, as computed by the CRC peripheral in its reset state.
+pub const CRC32_MPEG2: CrcParams = CrcParams {
+    poly: 0x04C1_1DB7,
+    init: 0xFFFF_FFFF,
+    reflect_in: false,
+    reflect_out: false,
+    xor_out: 0x0000_0000,
+};
+
+/// An incremental 32-bit checksum.
+pub trait Checksum {
+    /// Start a new calculation.
+    fn reset(&mut self);
+    /// Add `data` to the calculation.
+    fn update(&mut self, data: &[u8]);
+    /// The checksum over all data since the last reset.
+    fn finish(&self) -> u32;
+}
+
+/// Bitwise software CRC-32.
+pub struct SoftwareCrc {
+    params: CrcParams,
+    state: u32,
+}
+
+impl SoftwareCrc {
+    pub fn new(params: CrcParams) -> Self {
+        Self {
+            params,
+            state: params.init,
+        }
+    }
+}
+
+impl Checksum for SoftwareCrc {
+    fn reset(&mut self) {
+        self.state = self.params.init;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            let byte = if self.params.reflect_in {
+                byte.reverse_bits()
+            } else {
+                byte
+            };
+            self.state ^= (byte as u32) << 24;
+            for _ in 0..8 {
+                self.state = if self.state & 0x8000_0000 != 0 {
+                    (self.state << 1) ^ self.params.poly
+                } else {
+                    self.state << 1
+                };
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        let state = if self.params.reflect_out {
+            self.state.reverse_bits()
+        } else {
+            self.state
+        };
+        state ^ self.params.xor_out
+    }
+}
+
+/// CRC-32 using the STM32 CRC peripheral.
+#[cfg(target_os = "none")]
+pub struct HardwareCrc<'d> {
+    crc: Crc<'d>,
+    params: CrcParams,
+    state: u32,
+}
+
+#[cfg(target_os = "none")]
+impl<'d> HardwareCrc<'d> {
+    /// Take over the CRC peripheral and configure it for `params`.
+    pub fn new(peri: Peri<'d, CRC>, params: CrcParams) -> Self {
+        let reverse_in = if params.reflect_in {
+            InputReverseConfig::Byte
+        } else {
+            InputReverseConfig::None
+        };
+        let config = Config::new(
+            reverse_in,
+            params.reflect_out,
+            PolySize::Width32,
+            params.init,
+            params.poly,
+        )
+        .unwrap();
+        let mut crc = Crc::new(peri, config);
+        crc.reset();
+
+        Self {
+            state: crc.read(),
+            crc,
+            params,
+        }
+    }
+}
+
+#[cfg(target_os = "none")]
+impl Checksum for HardwareCrc<'_> {
+    fn reset(&mut self) {
+        self.crc.reset();
+        self.state = self.crc.read();
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.state = self.crc.feed_bytes(data);
+    }
+
+    fn finish(&self) -> u32 {
+        // The peripheral already applied the output reflection.
+        self.state ^ self.params.xor_out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The catalogued check input of CRC parameter sets.
+    const CHECK_INPUT: &[u8] = b"123456789";
+
+    #[test]
+    fn software_crc_matches_check_values() {
+        for (params, check) in [(CRC32_ISO_HDLC, 0xCBF4_3926), (CRC32_MPEG2, 0x0376_E6E7)] {
+            let mut crc = SoftwareCrc::new(params);
+            crc.update(CHECK_INPUT);
+            assert_eq!(crc.finish(), check);
+        }
+    }
+
+    #[test]
+    fn software_crc_is_incremental() {
+        let mut crc = SoftwareCrc::new(CRC32_ISO_HDLC);
+        crc.update(b"garbage");
+        crc.reset();
+        for chunk in CHECK_INPUT.chunks(4) {
+            crc.update(chunk);
+        }
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+}
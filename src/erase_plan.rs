@@ -0,0 +1,237 @@
+// Erase planning for NOR flash with 4K sectors and 64K blocks.
+//
+// An erase range is split into a sequence of erase operations: 64K block
+// erases for the block-aligned interior of the range, and 4K sector erases
+// for the unaligned edges. The plan is pure data, so it can be built and
+// inspected without any hardware attached, before handing it to the flash
+// driver for execution.
+
+/// Size of the smallest erasable unit.
+pub const SECTOR_SIZE: u32 = 4 * 1024;
+
+/// Size of the largest erasable unit, short of a chip erase.
+pub const BLOCK_SIZE: u32 = 64 * 1024;
+
+/// Reasons an erase range can be rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum EraseError {
+    /// The start or end address is not aligned to a sector boundary.
+    Unaligned,
+    /// The end address lies before the start address.
+    InvalidRange,
+    /// The range extends beyond the end of the device.
+    OutOfBounds,
+}
+
+/// A single erase operation, with the address of the first byte it erases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum EraseOp {
+    /// Erase one 4K sector.
+    Sector(u32),
+    /// Erase one 64K block.
+    Block64k(u32),
+}
+
+impl EraseOp {
+    /// Address of the first byte erased by this operation.
+    pub fn addr(&self) -> u32 {
+        match *self {
+            EraseOp::Sector(addr) | EraseOp::Block64k(addr) => addr,
+        }
+    }
+
+    /// Number of bytes erased by this operation.
+    pub fn size(&self) -> u32 {
+        match self {
+            EraseOp::Sector(_) => SECTOR_SIZE,
+            EraseOp::Block64k(_) => BLOCK_SIZE,
+        }
+    }
+}
+
+/// Validated plan to erase the address range `start..end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub struct ErasePlan {
+    start: u32,
+    end: u32,
+    skip_blank: bool,
+}
+
+impl ErasePlan {
+    /// Plan an erase of `start..end` on a device of `device_size` bytes.
+    ///
+    /// Both `start` and `end` must be sector-aligned. If `skip_blank` is set,
+    /// the executor is allowed to skip operations whose area already reads
+    /// as fully erased.
+    pub fn new(
+        start: u32,
+        end: u32,
+        device_size: u32,
+        skip_blank: bool,
+    ) -> Result<Self, EraseError> {
+        if !start.is_multiple_of(SECTOR_SIZE) || !end.is_multiple_of(SECTOR_SIZE) {
+            return Err(EraseError::Unaligned);
+        }
+        if end < start {
+            return Err(EraseError::InvalidRange);
+        }
+        if end > device_size {
+            return Err(EraseError::OutOfBounds);
+        }
+        Ok(Self {
+            start,
+            end,
+            skip_blank,
+        })
+    }
+
+    /// First address of the range.
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    /// One past the last address of the range.
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    /// Total number of bytes erased by the plan.
+    pub fn len(&self) -> u32 {
+        self.end - self.start
+    }
+
+    /// Whether the plan erases nothing at all.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Whether the executor may skip already-blank areas.
+    pub fn skip_blank(&self) -> bool {
+        self.skip_blank
+    }
+
+    /// The erase operations, in ascending address order.
+    pub fn ops(&self) -> EraseOps {
+        EraseOps {
+            place: self.start,
+            end: self.end,
+        }
+    }
+}
+
+/// Iterator over the operations of an [`ErasePlan`].
+#[derive(Debug, Clone)]
+pub struct EraseOps {
+    place: u32,
+    end: u32,
+}
+
+impl Iterator for EraseOps {
+    type Item = EraseOp;
+
+    fn next(&mut self) -> Option<EraseOp> {
+        if self.place >= self.end {
+            return None;
+        }
+
+        // Use a block erase whenever a whole aligned block fits in what is
+        // left of the range, and fall back to sector erases at the edges.
+        let op = if self.place.is_multiple_of(BLOCK_SIZE) && self.end - self.place >= BLOCK_SIZE {
+            EraseOp::Block64k(self.place)
+        } else {
+            EraseOp::Sector(self.place)
+        };
+        self.place += op.size();
+        Some(op)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE_SIZE: u32 = 32 * 1024 * 1024;
+
+    fn ops(start: u32, end: u32) -> Vec<EraseOp> {
+        ErasePlan::new(start, end, DEVICE_SIZE, false)
+            .unwrap()
+            .ops()
+            .collect()
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        let plan = |start, end| ErasePlan::new(start, end, DEVICE_SIZE, false);
+        assert_eq!(plan(0x100, 0x2000), Err(EraseError::Unaligned));
+        assert_eq!(plan(0x1000, 0x2001), Err(EraseError::Unaligned));
+        assert_eq!(plan(0x2000, 0x1000), Err(EraseError::InvalidRange));
+        assert_eq!(
+            plan(DEVICE_SIZE - SECTOR_SIZE, DEVICE_SIZE + SECTOR_SIZE),
+            Err(EraseError::OutOfBounds)
+        );
+        assert!(plan(DEVICE_SIZE - SECTOR_SIZE, DEVICE_SIZE).is_ok());
+    }
+
+    #[test]
+    fn empty_range() {
+        let plan = ErasePlan::new(0x3000, 0x3000, DEVICE_SIZE, true).unwrap();
+        assert!(plan.is_empty());
+        assert!(plan.skip_blank());
+        assert_eq!(plan.ops().next(), None);
+    }
+
+    #[test]
+    fn sectors_only() {
+        // Less than a block, and a whole block's worth that is not aligned.
+        assert_eq!(
+            ops(0x1000, 0x4000),
+            [
+                EraseOp::Sector(0x1000),
+                EraseOp::Sector(0x2000),
+                EraseOp::Sector(0x3000)
+            ]
+        );
+        let unaligned = ops(0x8000, 0x18000);
+        assert_eq!(unaligned.len(), 16);
+        assert!(unaligned.iter().all(|op| matches!(op, EraseOp::Sector(_))));
+    }
+
+    #[test]
+    fn sector_edges_around_blocks() {
+        let plan = ErasePlan::new(0xE000, 0x31000, DEVICE_SIZE, false).unwrap();
+        let ops: Vec<_> = plan.ops().collect();
+        assert_eq!(
+            ops,
+            [
+                EraseOp::Sector(0xE000),
+                EraseOp::Sector(0xF000),
+                EraseOp::Block64k(0x10000),
+                EraseOp::Block64k(0x20000),
+                EraseOp::Sector(0x30000),
+            ]
+        );
+        // The operations cover the range exactly, in order.
+        let mut place = plan.start();
+        for op in &ops {
+            assert_eq!(op.addr(), place);
+            place += op.size();
+        }
+        assert_eq!(place, plan.end());
+        assert_eq!(plan.len(), 0x23000);
+    }
+
+    #[test]
+    fn aligned_blocks_only() {
+        assert_eq!(
+            ops(0x20000, 0x50000),
+            [
+                EraseOp::Block64k(0x20000),
+                EraseOp::Block64k(0x30000),
+                EraseOp::Block64k(0x40000),
+            ]
+        );
+    }
+}
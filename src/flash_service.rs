@@ -0,0 +1,381 @@
+// Shared access to the flash from several tasks.
+//
+// The driver is a single owned struct, so one task (the service) owns it and
+// executes requests sent to it through channels. Other tasks hold a
+// `FlashClient`, which sends a request and waits for the reply. There is one
+// channel per priority: pending high-priority requests are always served
+// before normal ones.
+//
+// Data travels by value, in chunks of up to `CHUNK_SIZE` bytes, so a client
+// that is dropped (or whose request future is dropped) never leaves the
+// service with a dangling buffer. Larger reads and writes are split by the
+// client; other clients' requests may be served in between chunks.
+//
+// A high-priority read arriving during a program or erase does not wait for
+// it to finish: the operation is suspended, the read served, and the
+// operation resumed. To guarantee progress, the operation always runs for
+// `MIN_RUN_TIME` between two suspensions, and is suspended at most
+// `MAX_SUSPENSIONS` times. A read of the area being programmed or erased
+// would see it half-changed, so it is deferred until the operation completes,
+// like high-priority writes and erases. Later reads are still served.
+//
+// Clients also split erases, into single block or sector erases, so one
+// request is at most one 64K block erase or two page programs. This bounds
+// the latency of high-priority requests: a read waits `MIN_RUN_TIME` at most,
+// unless it overlaps the operation or the suspensions are used up, and then
+// for the rest of that request. A write or erase waits for the rest of the
+// current request, plus the high-priority requests ahead of it.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use heapless::{Deque, Vec};
+
+use crate::erase_plan::ErasePlan;
+use crate::mx25uw25645g::{FlashError, MEMORY_FLASH_BYTES, OpiFlashMemory, PendingOp};
+
+/// Largest amount of data carried by one request or reply: one flash page.
+pub const CHUNK_SIZE: usize = 256;
+
+/// Maximum number of clients (including clones) alive at the same time.
+pub const MAX_CLIENTS: usize = 8;
+
+/// Requests that can wait in each priority queue.
+const QUEUE_DEPTH: usize = 4;
+
+/// Page size of the flash: writes are split into page programs.
+const PAGE_SIZE: usize = 256;
+
+/// Time a program or erase runs after being started or resumed, before it may
+/// be suspended. The flash needs some time after a resume to make progress,
+/// and this is also the latency added to high-priority reads that can be
+/// served during the operation.
+const MIN_RUN_TIME: Duration = Duration::from_millis(1);
+
+/// Maximum number of suspensions of one program or erase operation. Once
+/// reached, reads wait for the operation to complete.
+const MAX_SUSPENSIONS: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Priority {
+    /// Served before any normal request, e.g. for lookups by the logger.
+    High,
+    /// Bulk work, e.g. firmware updates.
+    Normal,
+}
+
+enum Command {
+    Read {
+        addr: u32,
+        len: usize,
+    },
+    Write {
+        addr: u32,
+        data: Vec<u8, CHUNK_SIZE>,
+    },
+    Erase {
+        start: u32,
+        end: u32,
+    },
+}
+
+enum Response {
+    Read(Vec<u8, CHUNK_SIZE>),
+    Done(Result<(), FlashError>),
+}
+
+struct Request {
+    command: Command,
+    /// Index of the reply slot of the client.
+    slot: usize,
+    seq: u32,
+}
+
+struct Reply {
+    seq: u32,
+    response: Response,
+}
+
+/// The queues and reply slots connecting clients to the service task.
+/// Typically a `static`.
+pub struct FlashService {
+    high: Channel<CriticalSectionRawMutex, Request, QUEUE_DEPTH>,
+    normal: Channel<CriticalSectionRawMutex, Request, QUEUE_DEPTH>,
+    replies: [Signal<CriticalSectionRawMutex, Reply>; MAX_CLIENTS],
+    /// Bit n is set while reply slot n belongs to a client.
+    slots_used: AtomicU32,
+    /// Sequence number of the next request. Unique across clients, so a
+    /// reused slot cannot mistake a stale reply for its own.
+    next_seq: AtomicU32,
+}
+
+impl FlashService {
+    pub const fn new() -> Self {
+        Self {
+            high: Channel::new(),
+            normal: Channel::new(),
+            replies: [const { Signal::new() }; MAX_CLIENTS],
+            slots_used: AtomicU32::new(0),
+            next_seq: AtomicU32::new(0),
+        }
+    }
+
+    /// Create a new client, with normal priority.
+    /// Panics if `MAX_CLIENTS` clients already exist.
+    pub fn client(&'static self) -> FlashClient {
+        let mut slot = 0;
+        self.slots_used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                slot = (!used).trailing_zeros() as usize;
+                (slot < MAX_CLIENTS).then_some(used | (1 << slot))
+            })
+            .expect("Too many flash clients");
+
+        FlashClient {
+            service: self,
+            slot,
+            priority: Priority::Normal,
+        }
+    }
+
+    /// Serve requests forever. Call from the task owning the flash.
+    pub async fn run(&self, flash: &mut OpiFlashMemory) -> ! {
+        // High-priority requests that arrived during a program or erase,
+        // but could not be served by suspending it.
+        let mut deferred = Deque::new();
+
+        loop {
+            // select() polls its first future first, so the high-priority
+            // queue wins when both have requests waiting.
+            let request = match deferred.pop_front() {
+                Some(request) => request,
+                None => match select(self.high.receive(), self.normal.receive()).await {
+                    Either::First(request) | Either::Second(request) => request,
+                },
+            };
+            let response = self.execute(flash, request.command, &mut deferred).await;
+            self.reply(request.slot, request.seq, response);
+        }
+    }
+
+    fn reply(&self, slot: usize, seq: u32, response: Response) {
+        self.replies[slot].signal(Reply { seq, response });
+    }
+
+    async fn execute(
+        &self,
+        flash: &mut OpiFlashMemory,
+        command: Command,
+        deferred: &mut Deque<Request, QUEUE_DEPTH>,
+    ) -> Response {
+        match command {
+            Command::Read { addr, len } => Response::Read(read(flash, addr, len)),
+            Command::Write { addr, data } => {
+                Response::Done(self.write(flash, addr, &data, deferred).await)
+            }
+            Command::Erase { start, end } => {
+                Response::Done(self.erase(flash, start, end, deferred).await)
+            }
+        }
+    }
+
+    async fn write(
+        &self,
+        flash: &mut OpiFlashMemory,
+        addr: u32,
+        data: &[u8],
+        deferred: &mut Deque<Request, QUEUE_DEPTH>,
+    ) -> Result<(), FlashError> {
+        let mut place = addr;
+        let mut chunk_start = 0;
+        while chunk_start < data.len() {
+            let max_chunk_size = PAGE_SIZE - (place as usize % PAGE_SIZE);
+            let chunk_size = max_chunk_size.min(data.len() - chunk_start);
+            let chunk = &data[chunk_start..(chunk_start + chunk_size)];
+            let op = flash.start_program(place, chunk);
+            self.wait_serving_reads(flash, op, deferred).await;
+            place += chunk_size as u32;
+            chunk_start += chunk_size;
+        }
+        flash.verify_written(addr, data)?;
+        Ok(())
+    }
+
+    async fn erase(
+        &self,
+        flash: &mut OpiFlashMemory,
+        start: u32,
+        end: u32,
+        deferred: &mut Deque<Request, QUEUE_DEPTH>,
+    ) -> Result<(), FlashError> {
+        let plan = ErasePlan::new(start, end, MEMORY_FLASH_BYTES, false)?;
+        for op in plan.ops() {
+            let op = flash.start_erase_op(op);
+            self.wait_serving_reads(flash, op, deferred).await;
+        }
+        Ok(())
+    }
+
+    /// Wait for `op` to complete, suspending it to serve high-priority reads.
+    /// Other high-priority requests are added to `deferred`.
+    async fn wait_serving_reads(
+        &self,
+        flash: &mut OpiFlashMemory,
+        op: PendingOp,
+        deferred: &mut Deque<Request, QUEUE_DEPTH>,
+    ) {
+        let mut suspensions = 0;
+        loop {
+            if suspensions == MAX_SUSPENSIONS || deferred.is_full() {
+                flash.wait_pending(op).await;
+                return;
+            }
+
+            let urgent = async {
+                Timer::after(MIN_RUN_TIME).await;
+                self.high.receive().await
+            };
+            let request = match select(flash.wait_pending(op), urgent).await {
+                Either::First(()) => return,
+                Either::Second(request) => request,
+            };
+
+            let (addr, len) = match request.command {
+                Command::Read { addr, len } if !op.overlaps(addr, len) => (addr, len),
+                _ => {
+                    // Cannot fail: checked above.
+                    let _ = deferred.push_back(request);
+                    continue;
+                }
+            };
+            // If the operation completed meanwhile, there is nothing to
+            // resume, and the next wait returns immediately.
+            let suspended = flash.suspend();
+            let data = read(flash, addr, len);
+            if suspended {
+                flash.resume();
+                suspensions += 1;
+            }
+            self.reply(request.slot, request.seq, Response::Read(data));
+        }
+    }
+}
+
+fn read(flash: &mut OpiFlashMemory, addr: u32, len: usize) -> Vec<u8, CHUNK_SIZE> {
+    let mut data = Vec::new();
+    data.resize(len, 0).unwrap();
+    flash.read_memory(addr, &mut data);
+    data
+}
+
+impl Default for FlashService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handle to the flash service. Each clone has its own reply slot, so clones
+/// can be handed to different tasks.
+pub struct FlashClient {
+    service: &'static FlashService,
+    slot: usize,
+    priority: Priority,
+}
+
+impl FlashClient {
+    /// Priority of the following requests of this client.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Read `buf.len()` bytes at `addr`.
+    pub async fn read(&mut self, addr: u32, buf: &mut [u8]) {
+        let mut place = addr;
+        for chunk in buf.chunks_mut(CHUNK_SIZE) {
+            let command = Command::Read {
+                addr: place,
+                len: chunk.len(),
+            };
+            match self.request(command).await {
+                Response::Read(data) => chunk.copy_from_slice(&data),
+                Response::Done(_) => unreachable!(),
+            }
+            place += chunk.len() as u32;
+        }
+    }
+
+    /// Write `data` at `addr`. The area must have been erased.
+    pub async fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        let mut place = addr;
+        for chunk in data.chunks(CHUNK_SIZE) {
+            let command = Command::Write {
+                addr: place,
+                data: Vec::from_slice(chunk).unwrap(),
+            };
+            self.request_done(command).await?;
+            place += chunk.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// Erase the sector-aligned range `start..end`.
+    pub async fn erase(&mut self, start: u32, end: u32) -> Result<(), FlashError> {
+        let plan = ErasePlan::new(start, end, MEMORY_FLASH_BYTES, false)?;
+        for op in plan.ops() {
+            let command = Command::Erase {
+                start: op.addr(),
+                end: op.addr() + op.size(),
+            };
+            self.request_done(command).await?;
+        }
+        Ok(())
+    }
+
+    async fn request_done(&mut self, command: Command) -> Result<(), FlashError> {
+        match self.request(command).await {
+            Response::Done(result) => result,
+            Response::Read(_) => unreachable!(),
+        }
+    }
+
+    async fn request(&mut self, command: Command) -> Response {
+        let seq = self.service.next_seq.fetch_add(1, Ordering::Relaxed);
+        let request = Request {
+            command,
+            slot: self.slot,
+            seq,
+        };
+        match self.priority {
+            Priority::High => self.service.high.send(request).await,
+            Priority::Normal => self.service.normal.send(request).await,
+        }
+
+        // Skip the reply to an earlier request whose future was dropped.
+        loop {
+            let reply = self.service.replies[self.slot].wait().await;
+            if reply.seq == seq {
+                return reply.response;
+            }
+        }
+    }
+}
+
+impl Clone for FlashClient {
+    fn clone(&self) -> Self {
+        let mut client = self.service.client();
+        client.priority = self.priority;
+        client
+    }
+}
+
+impl Drop for FlashClient {
+    fn drop(&mut self) {
+        self.service
+            .slots_used
+            .fetch_and(!(1 << self.slot), Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,379 @@
+// Firmware image layout of the ACTIVE and DFU partitions.
+//
+// An image starts with a header slot of `HEADER_SLOT_SIZE` bytes, so the
+// payload (starting with the application's vector table) stays aligned as
+// VTOR requires. The payload is followed by an optional trailer, e.g. a
+// signature.
+//
+//   offset  size  field
+//        0     4  magic, "H7SB"
+//        4     2  header version
+//        6     2  header slot size (offset of the payload)
+//        8     4  image version
+//       12     4  load address of the payload (in the memory-mapped window)
+//       16     4  payload size
+//       20     4  entry point (reset handler, Thumb address), matching the
+//                 reset vector of the payload's vector table
+//       24     4  flags
+//       28     4  trailer size
+//       32     4  CRC-32/ISO-HDLC of the payload
+//       36     4  security version (anti-rollback, see `rollback`)
+//       40    20  reserved, zero
+//       60     4  CRC-32/ISO-HDLC of bytes 0..60
+//
+// All fields are little-endian. The rest of the header slot is 0xFF.
+//
+// This module only depends on `core` and the `Checksum` trait, so images can
+// be built and checked on the host with `SoftwareCrc`.
+
+use core::ops::Range;
+
+use crate::checksum::Checksum;
+
+pub const MAGIC: u32 = u32::from_le_bytes(*b"H7SB");
+pub const HEADER_VERSION: u16 = 1;
+
+/// Bytes of the header proper.
+pub const HEADER_LEN: usize = 64;
+
+/// Space reserved for the header before the payload.
+pub const HEADER_SLOT_SIZE: usize = 1024;
+
+/// The image is followed by a trailer of `trailer_size` bytes.
+pub const FLAG_TRAILER: u32 = 1 << 0;
+
+const HEADER_CRC_OFFSET: usize = 60;
+
+/// Why an image was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum ImageError {
+    /// Fewer bytes than a header.
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u16),
+    HeaderCrc,
+    /// The header slot size is not the one this bootloader expects.
+    BadHeaderSize,
+    /// Reserved bits or bytes are not zero.
+    Reserved,
+    /// Header, payload and trailer do not fit in the partition.
+    TooLarge,
+    /// The payload would not execute where the partition is mapped.
+    BadLoadAddress,
+    /// The entry point is not a Thumb address inside the payload.
+    BadEntryPoint,
+    /// The entry point is not the reset vector of the payload's vector
+    /// table, which is what actually starts the application.
+    EntryPointMismatch,
+    PayloadCrc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub struct ImageHeader {
+    pub image_version: u32,
+    pub load_address: u32,
+    pub image_size: u32,
+    pub entry_point: u32,
+    pub flags: u32,
+    pub trailer_size: u32,
+    pub payload_crc: u32,
+    /// Raised by releases fixing vulnerabilities, so older images can be
+    /// refused. Zero in images built before it existed.
+    pub security_version: u32,
+}
+
+impl ImageHeader {
+    /// Parse and check the header at the start of `bytes`, on its own: magic,
+    /// version, header CRC and reserved fields.
+    pub fn parse(bytes: &[u8], crc: &mut impl Checksum) -> Result<Self, ImageError> {
+        let bytes: &[u8; HEADER_LEN] = bytes
+            .get(..HEADER_LEN)
+            .ok_or(ImageError::Truncated)?
+            .try_into()
+            .unwrap();
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        if u32_at(0) != MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        if u16_at(4) != HEADER_VERSION {
+            return Err(ImageError::UnsupportedVersion(u16_at(4)));
+        }
+        crc.reset();
+        crc.update(&bytes[..HEADER_CRC_OFFSET]);
+        if crc.finish() != u32_at(HEADER_CRC_OFFSET) {
+            return Err(ImageError::HeaderCrc);
+        }
+        if u16_at(6) as usize != HEADER_SLOT_SIZE {
+            return Err(ImageError::BadHeaderSize);
+        }
+
+        let header = Self {
+            image_version: u32_at(8),
+            load_address: u32_at(12),
+            image_size: u32_at(16),
+            entry_point: u32_at(20),
+            flags: u32_at(24),
+            trailer_size: u32_at(28),
+            payload_crc: u32_at(32),
+            security_version: u32_at(36),
+        };
+        let trailer_consistent = (header.flags & FLAG_TRAILER != 0) == (header.trailer_size != 0);
+        if header.flags & !FLAG_TRAILER != 0
+            || !trailer_consistent
+            || bytes[40..HEADER_CRC_OFFSET].iter().any(|&b| b != 0)
+        {
+            return Err(ImageError::Reserved);
+        }
+        Ok(header)
+    }
+
+    /// Serialize the header into the start of `slot`, which must hold at
+    /// least `HEADER_LEN` bytes, and fill the rest with 0xFF.
+    pub fn write(&self, slot: &mut [u8], crc: &mut impl Checksum) {
+        slot.fill(0xFF);
+        let bytes = &mut slot[..HEADER_LEN];
+        bytes.fill(0);
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..6].copy_from_slice(&HEADER_VERSION.to_le_bytes());
+        bytes[6..8].copy_from_slice(&(HEADER_SLOT_SIZE as u16).to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.image_version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.load_address.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.image_size.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.entry_point.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.trailer_size.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.payload_crc.to_le_bytes());
+        bytes[36..40].copy_from_slice(&self.security_version.to_le_bytes());
+        crc.reset();
+        crc.update(&bytes[..HEADER_CRC_OFFSET]);
+        bytes[HEADER_CRC_OFFSET..].copy_from_slice(&crc.finish().to_le_bytes());
+    }
+
+    /// Offsets of the payload, relative to the start of the image.
+    pub fn payload_range(&self) -> Range<usize> {
+        HEADER_SLOT_SIZE..HEADER_SLOT_SIZE + self.image_size as usize
+    }
+
+    /// Offsets of the trailer (empty without one), relative to the start of
+    /// the image.
+    pub fn trailer_range(&self) -> Range<usize> {
+        let start = self.payload_range().end;
+        start..start + self.trailer_size as usize
+    }
+
+    /// Check that the image fits in a partition of `partition_len` bytes,
+    /// mapped at `mapped_base`, and would execute from there.
+    pub fn check_placement(
+        &self,
+        mapped_base: u32,
+        partition_len: usize,
+    ) -> Result<(), ImageError> {
+        let total = HEADER_SLOT_SIZE
+            .checked_add(self.image_size as usize)
+            .and_then(|len| len.checked_add(self.trailer_size as usize));
+        if total.is_none_or(|total| total > partition_len) {
+            return Err(ImageError::TooLarge);
+        }
+
+        let payload_start = mapped_base + HEADER_SLOT_SIZE as u32;
+        if self.load_address != payload_start {
+            return Err(ImageError::BadLoadAddress);
+        }
+        let entry = self.entry_point & !1;
+        if self.entry_point & 1 == 0
+            || entry < payload_start
+            || entry >= payload_start + self.image_size
+        {
+            return Err(ImageError::BadEntryPoint);
+        }
+        Ok(())
+    }
+
+    /// Check the CRC of `payload`, the bytes at `payload_range()`.
+    pub fn check_payload(&self, payload: &[u8], crc: &mut impl Checksum) -> Result<(), ImageError> {
+        crc.reset();
+        crc.update(payload);
+        if payload.len() != self.image_size as usize || crc.finish() != self.payload_crc {
+            return Err(ImageError::PayloadCrc);
+        }
+        Ok(())
+    }
+
+    /// Check that the reset vector, the second entry of the vector table at
+    /// the start of `payload`, is the entry point.
+    pub fn check_reset_vector(&self, payload: &[u8]) -> Result<(), ImageError> {
+        let reset_vector = payload
+            .get(4..8)
+            .ok_or(ImageError::BadEntryPoint)?
+            .try_into()
+            .map(u32::from_le_bytes)
+            .unwrap();
+        if reset_vector != self.entry_point {
+            return Err(ImageError::EntryPointMismatch);
+        }
+        Ok(())
+    }
+}
+
+/// Fully validate the image occupying `partition` (mapped at `mapped_base`):
+/// header, placement, payload CRC and reset vector.
+pub fn validate(
+    partition: &[u8],
+    mapped_base: u32,
+    crc: &mut impl Checksum,
+) -> Result<ImageHeader, ImageError> {
+    let header = ImageHeader::parse(partition, crc)?;
+    header.check_placement(mapped_base, partition.len())?;
+    let payload = &partition[header.payload_range()];
+    header.check_payload(payload, crc)?;
+    header.check_reset_vector(payload)?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::{CRC32_ISO_HDLC, SoftwareCrc};
+
+    const MAPPED_BASE: u32 = 0x7002_0000;
+    const PARTITION_LEN: usize = 4096;
+    const PAYLOAD_LEN: usize = 2048;
+
+    fn crc() -> SoftwareCrc {
+        SoftwareCrc::new(CRC32_ISO_HDLC)
+    }
+
+    /// A valid image, padded with 0xFF to `PARTITION_LEN`.
+    fn build_image() -> ([u8; PARTITION_LEN], ImageHeader) {
+        let mut image = [0xFF; PARTITION_LEN];
+        let load_address = MAPPED_BASE + HEADER_SLOT_SIZE as u32;
+        let entry_point = (load_address + 0x100) | 1;
+        let payload = &mut image[HEADER_SLOT_SIZE..HEADER_SLOT_SIZE + PAYLOAD_LEN];
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        payload[0..4].copy_from_slice(&0x2400_1000u32.to_le_bytes());
+        payload[4..8].copy_from_slice(&entry_point.to_le_bytes());
+
+        let mut crc = crc();
+        crc.update(payload);
+        let header = ImageHeader {
+            image_version: 3,
+            load_address,
+            image_size: PAYLOAD_LEN as u32,
+            entry_point,
+            flags: 0,
+            trailer_size: 0,
+            payload_crc: crc.finish(),
+            security_version: 2,
+        };
+        header.write(&mut image[..HEADER_SLOT_SIZE], &mut crc);
+        (image, header)
+    }
+
+    /// Rewrite the header CRC after corrupting the header on purpose.
+    fn fix_header_crc(image: &mut [u8]) {
+        let mut crc = crc();
+        crc.update(&image[..HEADER_CRC_OFFSET]);
+        let header_crc = crc.finish().to_le_bytes();
+        image[HEADER_CRC_OFFSET..HEADER_LEN].copy_from_slice(&header_crc);
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let (image, header) = build_image();
+        assert_eq!(ImageHeader::parse(&image, &mut crc()), Ok(header));
+        assert!(
+            image[HEADER_LEN..HEADER_SLOT_SIZE]
+                .iter()
+                .all(|&b| b == 0xFF)
+        );
+        assert_eq!(validate(&image, MAPPED_BASE, &mut crc()), Ok(header));
+    }
+
+    #[test]
+    fn corrupted_header_is_refused() {
+        let (image, _) = build_image();
+        assert_eq!(
+            validate(&image[..HEADER_LEN - 1], MAPPED_BASE, &mut crc()),
+            Err(ImageError::Truncated)
+        );
+
+        let mut bad_magic = image;
+        bad_magic[0] ^= 1;
+        fix_header_crc(&mut bad_magic);
+        assert_eq!(
+            validate(&bad_magic, MAPPED_BASE, &mut crc()),
+            Err(ImageError::BadMagic)
+        );
+
+        for offset in [8, 36, HEADER_CRC_OFFSET] {
+            let mut flipped = image;
+            flipped[offset] ^= 0x10;
+            assert_eq!(
+                validate(&flipped, MAPPED_BASE, &mut crc()),
+                Err(ImageError::HeaderCrc)
+            );
+        }
+
+        let mut reserved = image;
+        reserved[40] = 1;
+        fix_header_crc(&mut reserved);
+        assert_eq!(
+            validate(&reserved, MAPPED_BASE, &mut crc()),
+            Err(ImageError::Reserved)
+        );
+    }
+
+    #[test]
+    fn misplaced_image_is_refused() {
+        let (image, _) = build_image();
+        assert_eq!(
+            validate(&image, MAPPED_BASE + 0x1000, &mut crc()),
+            Err(ImageError::BadLoadAddress)
+        );
+        assert_eq!(
+            validate(
+                &image[..HEADER_SLOT_SIZE + PAYLOAD_LEN - 1],
+                MAPPED_BASE,
+                &mut crc()
+            ),
+            Err(ImageError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn corrupted_payload_is_refused() {
+        let (image, _) = build_image();
+        let mut flipped = image;
+        flipped[HEADER_SLOT_SIZE + 100] ^= 0x80;
+        assert_eq!(
+            validate(&flipped, MAPPED_BASE, &mut crc()),
+            Err(ImageError::PayloadCrc)
+        );
+    }
+
+    #[test]
+    fn entry_point_must_be_the_reset_vector() {
+        let (mut image, header) = build_image();
+        let reset_vector = header.entry_point + 2;
+        let payload = &mut image[HEADER_SLOT_SIZE..HEADER_SLOT_SIZE + PAYLOAD_LEN];
+        payload[4..8].copy_from_slice(&reset_vector.to_le_bytes());
+        let mut crc = crc();
+        crc.update(payload);
+        let header = ImageHeader {
+            payload_crc: crc.finish(),
+            ..header
+        };
+        header.write(&mut image[..HEADER_SLOT_SIZE], &mut crc);
+        assert_eq!(
+            validate(&image, MAPPED_BASE, &mut crc),
+            Err(ImageError::EntryPointMismatch)
+        );
+    }
+}
@@ -0,0 +1,44 @@
+#![cfg_attr(not(test), no_std)]
+
+//! Octal flash support and bootloader building blocks for the Nucleo
+//! STM32H7S3L8 MB1737, with its MX25UW25645GXDI00 on XSPI2.
+//!
+//! Shared by the demo application (`src/main.rs`) and the bootloader
+//! (`src/bin/bootloader.rs`).
+//!
+//! Modules driving the hardware only build for the target. The others also
+//! build on the host, where their tests run: `cargo test-host`.
+
+#[cfg(target_os = "none")]
+pub mod ab;
+#[cfg(target_os = "none")]
+pub mod board;
+#[cfg(target_os = "none")]
+pub mod boot;
+#[cfg(target_os = "none")]
+pub mod cache;
+pub mod checksum;
+pub mod digest;
+pub mod erase_plan;
+#[cfg(target_os = "none")]
+pub mod flash_service;
+pub mod image;
+#[cfg(target_os = "none")]
+pub mod mapped;
+#[cfg(target_os = "none")]
+pub mod mpu;
+#[cfg(target_os = "none")]
+pub mod mx25uw25645g;
+#[cfg(target_os = "none")]
+pub mod nor_flash;
+pub mod power_loss;
+#[cfg(target_os = "none")]
+pub mod progress;
+#[cfg(target_os = "none")]
+pub mod ram_flash;
+#[cfg(target_os = "none")]
+pub mod rollback;
+pub mod signature;
+pub mod sim_flash;
+pub mod swap;
+pub mod xspi_clocks;
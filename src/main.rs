@@ -3,113 +3,61 @@
 
 //! For Nucleo STM32H7S3L8 MB1737, has MX25UW25645GXDI00
 //! Modified from: "examples/stm32h7rs/src/bin/xspi_memory_mapped.rs"
+//!
+//! Exercises the octal flash and the bootloader building blocks. Runs from
+//! the internal flash in place of the bootloader, so unlike an application
+//! started by it (see `boot` and `src/bin/app.rs`), it sets up the clocks
+//! with `embassy_stm32::init()`.
 
 use core::cmp::min;
+use core::ops::Range;
 
+use cortex_m::peripheral::DWT;
 use defmt::info;
+use embassy_embedded_hal::flash::partition::Partition;
 use embassy_executor::Spawner;
 use embassy_stm32::{
-    Config,
+    bind_interrupts,
     gpio::{Level, Output, Speed},
+    hash,
     mode::Blocking,
-    pac::rcc::vals::Plldivst, // TODO: not exported in embassy-stm32/src/rcc/h.rs.
-    rcc::{
-        AHBPrescaler, APBPrescaler, Hse, HseMode, Pll, PllDiv, PllMul, PllPreDiv, PllSource,
-        Sysclk, VoltageScale, mux::Xspisel,
-    },
-    time::Hertz,
-    xspi::{
-        AddressSize, ChipSelectHighTime, DummyCycles, FIFOThresholdLevel, Instance, MemorySize,
-        MemoryType, TransferConfig, WrapSize, Xspi, XspiWidth,
-    },
+    peripherals,
+    wdg::IndependentWatchdog,
+    xspi::{AddressSize, DummyCycles, Instance, TransferConfig, Xspi, XspiWidth},
 };
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
 use embassy_time::Timer;
+#[cfg(feature = "ab-slots")]
+use stm32h7s3l8_bootflash::ab;
+use stm32h7s3l8_bootflash::board::{self, XSPI_CLOCKS};
+use stm32h7s3l8_bootflash::cache::{self, CacheCoherency};
+use stm32h7s3l8_bootflash::checksum::{CRC32_ISO_HDLC, Checksum, HardwareCrc, SoftwareCrc};
+use stm32h7s3l8_bootflash::digest::{Digest, HardwareSha256, SHA256_LEN, SoftwareSha256};
+use stm32h7s3l8_bootflash::flash_service::FlashService;
+use stm32h7s3l8_bootflash::mapped::{self, MemoryMap};
+use stm32h7s3l8_bootflash::mx25uw25645g::{
+    MEMORY_BLOCK_SIZE, MEMORY_FLASH_BYTES, MEMORY_SECTOR_SIZE,
+};
+#[cfg(not(feature = "ab-slots"))]
+use stm32h7s3l8_bootflash::swap::FirmwareUpdater;
+use stm32h7s3l8_bootflash::{boot, mpu, mx25uw25645g, progress, ram_flash};
 use {defmt_rtt as _, panic_probe as _};
 
-mod mx25uw25645g;
+bind_interrupts!(struct Irqs {
+    XSPI2 => mx25uw25645g::StatusMatchInterruptHandler;
+    HASH => hash::InterruptHandler<peripherals::HASH>;
+});
 
 #[embassy_executor::main]
-async fn main(_spawner: Spawner) {
-    let mut config = Config::default();
-    {
-        config.rcc.hse = Some(Hse {
-            freq: Hertz(24_000_000),
-            mode: HseMode::Oscillator,
-        });
-        config.rcc.pll1 = Some(Pll {
-            source: PllSource::HSE,
-            prediv: PllPreDiv::DIV2,
-            mul: PllMul::MUL50,       // 600 MHz.
-            divp: Some(PllDiv::DIV1), // 600 MHz PLLCLK for SYSCLK.
-            divq: None,
-            divr: None,
-            divs: None,
-            divt: None,
-        });
-        // TODO: pll2 is not fully implemented yet: s,t channels are TODO in
-        // "embassy-stm32/src/rcc/h.rs" L735,737. Also, the "Plldivst" type is
-        // not properly exported. The RCC Clocks debug info shows zeros, but
-        // the PLL does generate the expected clocks.
-        // <https://github.com/embassy-rs/embassy/blob/main/embassy-stm32/src/rcc/h.rs>
-        // L735, L737
-        //
-        // Using PLL2_S as the clock source for the XSPI gives more flexibility
-        // in clock rates. Note that the PLL always has a 50% duty-cycle output
-        // on post-dividers divs and divt when VCOH is selected (PLLxVCOSEL = 0).
-        // We avoid the uncertainty here by generating double the required
-        // frequency, and then post-dividing by 2 in the xspi peripheral.
-        // For bus clk of 133 MHZ: 24 MHz /4 x133 /3 = 266 MHz (and then /2 in XSPI)
-        //                200 MHz: 24 MHz /3 x150 /3 = 400 MHz (and then /2 in XSPI)
-        config.rcc.pll2 = Some(Pll {
-            source: PllSource::HSE,
-            //prediv: PllPreDiv::DIV4, // 24 MHz / 4 = 6 MHz.
-            prediv: PllPreDiv::DIV3, // 24 MHz / 3 = 8 MHz.
-            //mul: PllMul::MUL133, // 6 MHz * 133 = 798 MHz.
-            //mul: PllMul::MUL150, // 8 MHz * 150 = 1200 MHz.
-            mul: PllMul::MUL150, // 8 MHz * N / 4 / 2 (<-- XSPI div/2) -> N = XSPI bus clock in OPI mode.
-            divp: Some(PllDiv::DIV1), // For debug: p,q,p,r are included in rcc Clocks log.
-            divq: None,
-            divr: None,
-            //divs: Some(Plldivst::DIV3), // 798 MHz / 3 = 266 MHz.
-            //divs: Some(Plldivst::DIV3), // 1200 MHz / 3 = 400 MHz.
-            divs: Some(Plldivst::DIV4),
-            divt: None,
-        });
-        config.rcc.sys = Sysclk::PLL1_P; // 600 MHz.
-        config.rcc.ahb_pre = AHBPrescaler::DIV2; // 300 MHz.
-        config.rcc.apb1_pre = APBPrescaler::DIV2; // 150 MHz.
-        config.rcc.apb2_pre = APBPrescaler::DIV2; // 150 MHz.
-        config.rcc.apb4_pre = APBPrescaler::DIV2; // 150 MHz.
-        config.rcc.apb5_pre = APBPrescaler::DIV2; // 150 MHz.
-        config.rcc.voltage_scale = VoltageScale::HIGH;
-        config.rcc.mux.xspi2sel = Xspisel::PLL2_S; // XSPI2 uses PLL2_S.
-    }
-    let p = embassy_stm32::init(config);
-
-    let spi_config = embassy_stm32::xspi::Config {
-        fifo_threshold: FIFOThresholdLevel::_4Bytes,
-        memory_type: MemoryType::Macronix,
-        delay_hold_quarter_cycle: true,
-        device_size: MemorySize::_32MiB,
-        chip_select_high_time: ChipSelectHighTime::_2Cycle,
-        free_running_clock: false,
-        clock_mode: false,
-        wrap_size: WrapSize::None,
-        // The initial bus clock is set at 100 MHz, as the flash will be in SPI
-        // mode after reset.
-        // - 266 MHz clock / (1 + 1) = 133 MHz.
-        // - 400 MHz clock / (3 + 1) = 100 MHz.
-        //   400 MHz clock / (1 + 1) = 200 MHz.
-        // Both are above the 50 MHz max for SPI READ instructions, so the FAST
-        // READ instruction must be used. The nucleo board's flash can run at up to
-        // 133 MHz in SPI mode and 200 MHz in OPI mode. This clock prescaler must be
-        // even, otherwise the clock will not have symmetric high and low times.
-        clock_prescaler: 3, // 400 MHz / (3 + 1) = 100 MHz (Even ratio: 3+1 = 4)
-        sample_shifting: false,
-        chip_select_boundary: 0,
-        max_transfer: 0,
-        refresh: 0,
-    };
+async fn main(spawner: Spawner) {
+    let p = embassy_stm32::init(board::config());
+    info!(
+        "XSPI kernel clock: {} Hz, SPI bus: {} Hz, OPI bus: {} Hz",
+        XSPI_CLOCKS.kernel_hz, XSPI_CLOCKS.spi_bus_hz, XSPI_CLOCKS.opi_bus_hz
+    );
+
+    let spi_config = board::xspi_config();
 
     let mut cor = cortex_m::Peripherals::take().unwrap();
 
@@ -118,6 +66,12 @@ async fn main(_spawner: Spawner) {
     // Note: Enabling data cache can cause issues with DMA transfers.
     cor.SCB.enable_dcache(&mut cor.CPUID);
 
+    // Keep the CPU away from the XSPI2 window while it is not memory-mapped.
+    mpu::init();
+
+    // Load the flash routines used while executing in place.
+    ram_flash::init();
+
     let xspi = embassy_stm32::xspi::Xspi::new_blocking_xspi_dqs(
         p.XSPI2, p.PN6, p.PN2, p.PN3, p.PN4, p.PN5, p.PN8, p.PN9, p.PN10, p.PN11, p.PN1, p.PN0, spi_config,
     );
@@ -141,8 +95,21 @@ async fn main(_spawner: Spawner) {
     assert_eq!(flash_id, [0xc2, 0x81, 0x39]);
     info!("FLASH ID: {=[u8]:x}", flash_id);
 
-    // Erase the first sector
-    flash.erase_sector(0);
+    // The tests below erase and write the flash: keep them out of the
+    // partitions, which hold the bootloader state and the images.
+    for partition in [
+        boot::state_partition(),
+        boot::active_partition(),
+        boot::dfu_partition(),
+    ] {
+        assert!(
+            TEST_END as usize <= partition.start || TEST_OFFSET as usize >= partition.end,
+            "Test area overlaps a partition"
+        );
+    }
+
+    // Erase the first sector of the test area
+    flash.erase_sector(TEST_OFFSET);
 
     // Write some data into the flash. This writes more than one page to test that functionality.
     let mut wr_buf = [0u8; 512];
@@ -150,7 +117,7 @@ async fn main(_spawner: Spawner) {
     for i in 0..512 {
         wr_buf[i] = base_number.wrapping_add(i as u8);
     }
-    flash.write_memory(0, &wr_buf);
+    flash.write_memory(TEST_OFFSET, &wr_buf);
 
     // Read the data back and verify it.
     // Note: because the blocking read_memory() does not use DMA internally,
@@ -162,22 +129,44 @@ async fn main(_spawner: Spawner) {
     //       mode, or when using DMA-based (async) operations.
     let mut rd_buf = [0u8; 512];
     let start_time = embassy_time::Instant::now();
-    flash.read_memory(0, &mut rd_buf);
+    flash.read_memory(TEST_OFFSET, &mut rd_buf);
     let elapsed = start_time.elapsed();
     info!("Read 512 bytes in {} us in SPI mode", elapsed.as_micros());
     info!("WRITE BUF: {=[u8]:#X}", wr_buf[0..32]);
     info!("READ BUF: {=[u8]:#X}", rd_buf[0..32]);
     assert_eq!(wr_buf, rd_buf, "Read buffer does not match write buffer");
 
-    flash.enable_mm();
-    info!("Enabled memory mapped mode");
-    let first_u32 = unsafe { *(0x70000000 as *const u32) };
-    assert_eq!(first_u32, 0x93929190);
-    info!("first_u32 {:08x}", first_u32);
-    let second_u32 = unsafe { *(0x70000004 as *const u32) };
-    assert_eq!(second_u32, 0x97969594);
-    info!("second_u32 {:08x}", second_u32);
-    flash.disable_mm();
+    {
+        let mapped = flash.map();
+        info!("Enabled memory mapped mode");
+        let test_area = &mapped[TEST_OFFSET as usize..];
+        let first_u32 = u32::from_le_bytes(test_area[0..4].try_into().unwrap());
+        assert_eq!(first_u32, 0x93929190);
+        info!("first_u32 {:08x}", first_u32);
+        let second_u32 = u32::from_le_bytes(test_area[4..8].try_into().unwrap());
+        assert_eq!(second_u32, 0x97969594);
+        info!("second_u32 {:08x}", second_u32);
+
+        // Checksum the written data through the CRC peripheral, and compare with
+        // the software implementation over the write buffer.
+        let mut hw_crc = HardwareCrc::new(p.CRC, CRC32_ISO_HDLC);
+        let start_time = embassy_time::Instant::now();
+        hw_crc.update(&test_area[..wr_buf.len()]);
+        let elapsed = start_time.elapsed();
+        let mut sw_crc = SoftwareCrc::new(CRC32_ISO_HDLC);
+        sw_crc.update(&wr_buf);
+        let crc = hw_crc.finish();
+        info!(
+            "CRC-32 of 512 bytes: {:08x} in {} us",
+            crc,
+            elapsed.as_micros()
+        );
+        assert_eq!(
+            crc,
+            sw_crc.finish(),
+            "Hardware CRC does not match software CRC"
+        );
+    }
     info!("Disabled memory mapped mode");
 
     let flash_id = flash.read_id();
@@ -186,11 +175,11 @@ async fn main(_spawner: Spawner) {
 
     let mut flash = flash.into_octo();
 
-    // After OPI mode is entered, change the bus clock to 200 MHz.
+    // After OPI mode is entered, change the bus clock to the OPI bus clock.
     // This will trigger the PHY auto tuning process.
-    // TODO: it won't go that high! At 150MHz, the first errors appear.
+    // TODO: it won't go up to 200 MHz! At 150MHz, the first errors appear.
     //       145 MHz is still fine.
-    flash.xspi.set_clock_prescaler(1); // 400 MHz / (1 + 1) = 200 MHz (Even ratio: 1+1 = 2)
+    flash.xspi.set_clock_prescaler(XSPI_CLOCKS.opi_prescaler);
 
     Timer::after_millis(100).await;
 
@@ -198,10 +187,10 @@ async fn main(_spawner: Spawner) {
     assert_eq!(flash_id, [0xc2, 0x81, 0x39]);
     info!("FLASH ID in OPI mode: {=[u8]:x}", flash_id);
 
-    flash.erase_sector(0);
+    flash.erase_sector(TEST_OFFSET);
 
     let mut rd_buf = [0u8; 512];
-    flash.read_memory(0, &mut rd_buf);
+    flash.read_memory(TEST_OFFSET, &mut rd_buf);
     info!("READ BUF after erase: {=[u8]:#X}", rd_buf[0..32]);
 
     assert_eq!(
@@ -209,9 +198,9 @@ async fn main(_spawner: Spawner) {
         "Read buffer is not all 0xFF after erase"
     );
 
-    //flash.write_memory(0, &wr_buf);
+    flash.write_memory(TEST_OFFSET, &wr_buf);
     let start = embassy_time::Instant::now();
-    flash.read_memory(0, &mut rd_buf);
+    flash.read_memory(TEST_OFFSET, &mut rd_buf);
     let elapsed = start.elapsed();
     info!("Read 512 bytes in {} us in OPI mode", elapsed.as_micros());
     info!("READ BUF after write: {=[u8]:#X}", rd_buf[0..32]);
@@ -220,15 +209,17 @@ async fn main(_spawner: Spawner) {
         "Read buffer does not match write buffer in OPI mode"
     );
 
-    flash.enable_mm();
-    info!("Enabled memory mapped mode in OPI mode");
-    let first_u32 = unsafe { *(0x70000000 as *const u32) };
-    assert_eq!(first_u32, 0x93929190);
-    info!("first_u32 {:08x}", first_u32);
-    let second_u32 = unsafe { *(0x70000004 as *const u32) };
-    assert_eq!(second_u32, 0x97969594);
-    info!("second_u32 {:08x}", second_u32);
-    flash.disable_mm();
+    {
+        let mapped = flash.map();
+        info!("Enabled memory mapped mode in OPI mode");
+        let test_area = &mapped[TEST_OFFSET as usize..];
+        let first_u32 = u32::from_le_bytes(test_area[0..4].try_into().unwrap());
+        assert_eq!(first_u32, 0x93929190);
+        info!("first_u32 {:08x}", first_u32);
+        let second_u32 = u32::from_le_bytes(test_area[4..8].try_into().unwrap());
+        assert_eq!(second_u32, 0x97969594);
+        info!("second_u32 {:08x}", second_u32);
+    }
     info!("Disabled memory mapped mode in OPI mode");
 
     // Reset back to SPI mode
@@ -237,19 +228,196 @@ async fn main(_spawner: Spawner) {
     assert_eq!(flash_id, [0xc2, 0x81, 0x39]);
     info!("FLASH ID back in SPI mode: {=[u8]:x}", flash_id);
 
+    // Hand the XSPI over to the octal DTR driver, and check that code can be
+    // executed in place from the memory-mapped flash.
+    flash.xspi.set_clock_prescaler(XSPI_CLOCKS.spi_prescaler);
+    let mut flash = mx25uw25645g::OpiFlashMemory::new(flash.xspi);
+    flash.set_clock_prescaler(XSPI_CLOCKS.opi_prescaler);
+    flash.enable_auto_polling(Irqs);
+    Timer::after_millis(100).await;
+
+    let code = unsafe {
+        let start = &raw const __xip_test_start;
+        let end = &raw const __xip_test_end;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    let mut log_progress = |p: progress::Progress| {
+        info!(
+            "Erasing: {}/{} bytes after {} us",
+            p.done,
+            p.total,
+            p.elapsed.as_micros()
+        )
+    };
+    flash
+        .erase_range_tracked(
+            XIP_TEST_OFFSET,
+            XIP_TEST_OFFSET + 4096,
+            false,
+            &mut log_progress,
+            &progress::CancelToken::new(),
+        )
+        .await
+        .unwrap();
+    flash
+        .write_memory_async(XIP_TEST_OFFSET, code)
+        .await
+        .unwrap();
+    {
+        let _mapped = flash.map();
+        let xip_probe_in_flash = unsafe {
+            core::mem::transmute::<usize, extern "C" fn(u32, u32) -> u32>(
+                (mapped::XSPI2_MAPPED_BASE + XIP_TEST_OFFSET as usize) | 1, // Thumb bit.
+            )
+        };
+        let result = xip_probe_in_flash(1234, 5678);
+        info!("XIP result: {:08x}", result);
+        assert_eq!(result, xip_probe(1234, 5678), "XIP result mismatch");
+    }
+
+    // Benchmark the SHA-256 implementations over the memory-mapped flash, as
+    // the bootloader uses them to check image signatures, and check that
+    // they agree.
+    cor.DCB.enable_trace();
+    DWT::unlock();
+    cor.DWT.enable_cycle_counter();
+    {
+        let mapped = flash.map();
+        let active = &mapped[boot::active_partition()];
+        let mut hw_sha = HardwareSha256::new(p.HASH, p.GPDMA1_CH0, Irqs);
+        let mut sw_sha = SoftwareSha256::new();
+        for size in SHA256_BENCH_SIZES {
+            let data = &active[..size];
+            let (hw_cycles, hw_digest) = sha256_cycles(&mut hw_sha, data);
+            let (sw_cycles, sw_digest) = sha256_cycles(&mut sw_sha, data);
+            assert_eq!(
+                hw_digest, sw_digest,
+                "Hardware SHA-256 does not match software SHA-256"
+            );
+            info!(
+                "SHA-256 of {} bytes: hardware {} cycles ({} cycles/byte), software {} cycles ({} cycles/byte)",
+                size,
+                hw_cycles,
+                hw_cycles as f32 / size as f32,
+                sw_cycles,
+                sw_cycles as f32 / size as f32
+            );
+        }
+    }
+
+    // From here on, the flash is shared through the flash service.
+    spawner.spawn(flash_task(flash).unwrap());
+    let mut client = FLASH_SERVICE.client();
+    let mut rd_buf = [0u8; 64];
+    let len = min(code.len(), rd_buf.len());
+    client.read(XIP_TEST_OFFSET, &mut rd_buf[..len]).await;
+    assert_eq!(rd_buf[..len], code[..len], "Flash service read mismatch");
+
+    // Firmware updates are written to DFU through the flash service, and
+    // swapped in by the bootloader once requested in BOOTLOADER_STATE. With
+    // A/B slots, they are written to the other slot instead.
+    let updater_flash = Mutex::<NoopRawMutex, _>::new(FLASH_SERVICE.client());
+    let partition = |range: Range<usize>| {
+        Partition::new(&updater_flash, range.start as u32, range.len() as u32)
+    };
+    #[cfg(not(feature = "ab-slots"))]
+    {
+        let mut updater = FirmwareUpdater::new(
+            partition(boot::dfu_partition()),
+            partition(boot::state_partition()),
+        );
+        let update_state = updater.get_state().await.unwrap();
+        info!("Firmware update state: {}", update_state);
+        // The self-tests passed: keep this image, if it runs on trial.
+        updater.mark_booted().await.unwrap();
+    }
+    #[cfg(feature = "ab-slots")]
+    {
+        let mut state_partition = partition(boot::state_partition());
+        info!("Running from slot {}", ab::Slot::running());
+        // The self-tests passed: keep this image, if it runs on trial.
+        ab::mark_booted(&mut state_partition).await.unwrap();
+    }
+
     info!("DONE");
 
     // Output pin PE3
     let mut led = Output::new(p.PD10, Level::Low, Speed::Low);
 
+    // After a trial boot, the watchdog keeps running. Feeding it does nothing
+    // if it was not started.
+    let mut watchdog = IndependentWatchdog::new(p.IWDG, boot::TRIAL_WATCHDOG_TIMEOUT_US);
+
     loop {
+        watchdog.pet();
         led.toggle();
         Timer::after_millis(1000).await;
     }
 }
 
+/// Data sizes of the SHA-256 benchmark, up to a whole ACTIVE partition.
+const SHA256_BENCH_SIZES: [usize; 4] = [1024, 16 * 1024, 128 * 1024, 512 * 1024];
+
+/// Runs per size of the SHA-256 benchmark. The fastest one is reported, so
+/// cold caches do not count.
+const SHA256_BENCH_RUNS: usize = 3;
+
+/// The fewest CPU cycles taken to hash `data` with `sha`, over
+/// `SHA256_BENCH_RUNS` runs, and the digest. The DWT cycle counter must run.
+fn sha256_cycles(sha: &mut impl Digest, data: &[u8]) -> (u32, [u8; SHA256_LEN]) {
+    let mut fewest = u32::MAX;
+    let mut digest = [0; SHA256_LEN];
+    for _ in 0..SHA256_BENCH_RUNS {
+        let start = DWT::cycle_count();
+        sha.update(data);
+        digest = sha.finish();
+        fewest = fewest.min(DWT::cycle_count().wrapping_sub(start));
+    }
+    (fewest, digest)
+}
+
+static FLASH_SERVICE: FlashService = FlashService::new();
+
+/// Owns the flash, and serves the requests of all `FlashClient`s.
+#[embassy_executor::task]
+async fn flash_task(mut flash: mx25uw25645g::OpiFlashMemory) {
+    FLASH_SERVICE.run(&mut flash).await
+}
+
 const MEMORY_PAGE_SIZE: usize = 256;
 
+/// Drop stale cache lines of the memory-mapped window after the demo drivers
+/// modified the flash at `addr..addr + len`, as the library driver does.
+fn sync_caches(addr: u32, len: usize) {
+    cache::sync_range(
+        mapped::XSPI2_MAPPED_BASE + addr as usize,
+        len,
+        CacheCoherency::Invalidate,
+    );
+}
+
+/// Flash area used by the tests of this demo: the two sectors after the last
+/// partition (DFU, see memory.x).
+const TEST_OFFSET: u32 = 0x0014_0000;
+const TEST_END: u32 = TEST_OFFSET + 2 * 4096;
+
+/// Flash offset of the XIP test code: the second sector of the test area.
+const XIP_TEST_OFFSET: u32 = TEST_OFFSET + 4096;
+
+// Bounds of the .xip_test section, see memory.x.
+unsafe extern "C" {
+    static __xip_test_start: u8;
+    static __xip_test_end: u8;
+}
+
+/// A position-independent function, copied to the external flash to test
+/// execution in place. It is kept in its own section so its size is known.
+#[unsafe(link_section = ".xip_test")]
+#[inline(never)]
+extern "C" fn xip_probe(a: u32, b: u32) -> u32 {
+    a.wrapping_mul(31).wrapping_add(b ^ 0x5A5A)
+}
+
 /// Implementation of access to flash chip using SPI.
 ///
 /// Chip commands are hardcoded as it depends on used chip.
@@ -498,37 +666,6 @@ impl<I: Instance> SpiFlashMemory<I> {
         memory
     }
 
-    pub fn disable_mm(&mut self) {
-        self.xspi.disable_memory_mapped_mode();
-    }
-
-    pub fn enable_mm(&mut self) {
-        let read_config = TransferConfig {
-            iwidth: XspiWidth::SING,
-            isize: AddressSize::_8bit,
-            adwidth: XspiWidth::SING,
-            adsize: AddressSize::_32bit,
-            dwidth: XspiWidth::SING,
-            instruction: Some(SpiCommand::FastRead4B as u32),
-            dummy: DummyCycles::_8,
-            ..Default::default()
-        };
-
-        let write_config = TransferConfig {
-            iwidth: XspiWidth::SING,
-            isize: AddressSize::_8bit,
-            adwidth: XspiWidth::SING,
-            adsize: AddressSize::_32bit,
-            dwidth: XspiWidth::SING,
-            instruction: Some(SpiCommand::PageProgram4B as u32),
-            dummy: DummyCycles::_0,
-            ..Default::default()
-        };
-        self.xspi
-            .enable_memory_mapped_mode(read_config, write_config)
-            .unwrap();
-    }
-
     fn into_octo(mut self) -> OpiFlashMemory<I> {
         self.enable_opi_mode();
         OpiFlashMemory { xspi: self.xspi }
@@ -617,16 +754,19 @@ impl<I: Instance> SpiFlashMemory<I> {
 
     pub fn erase_sector(&mut self, addr: u32) {
         self.perform_erase(addr, SpiCommand::SectorErase4B as u8);
+        sync_caches(addr, MEMORY_SECTOR_SIZE);
     }
 
     pub fn erase_block_64k(&mut self, addr: u32) {
         self.perform_erase(addr, SpiCommand::BlockErase4B as u8);
+        sync_caches(addr, MEMORY_BLOCK_SIZE);
     }
 
     pub fn erase_chip(&mut self) {
         self.enable_write();
         self.exec_command(SpiCommand::ChipErase as u8);
         self.wait_write_finish();
+        sync_caches(0, MEMORY_FLASH_BYTES as usize);
     }
 
     fn write_page(&mut self, addr: u32, buffer: &[u8], len: usize) {
@@ -666,6 +806,7 @@ impl<I: Instance> SpiFlashMemory<I> {
             left -= chunk_size;
             chunk_start += chunk_size;
         }
+        sync_caches(addr, buffer.len());
     }
 
     // Note: read_register cannot be used to read the configuration register 2 since there is an
@@ -746,50 +887,52 @@ impl<I: Instance> SpiFlashMemory<I> {
     }
 }
 
-impl<I: Instance> OpiFlashMemory<I> {
-    pub fn into_spi(mut self) -> SpiFlashMemory<I> {
-        self.disable_opi_mode();
-        SpiFlashMemory { xspi: self.xspi }
-    }
-
-    /// Disable OPI mode and return to SPI
-    pub fn disable_opi_mode(&mut self) {
-        // Clear SOPI and DOPI bits in CR2 volatile register
-        let cr2_0 = self.read_cr2(0x00000000);
-        self.write_cr2(0x00000000, cr2_0 & 0xFC); // Clear bits 0 and 1
+impl<I: Instance> MemoryMap for SpiFlashMemory<I> {
+    fn disable_mm(&mut self) {
+        mpu::set_mapped(false);
+        self.xspi.disable_memory_mapped_mode();
     }
 
-    /// Enable memory-mapped mode for OPI
-    pub fn enable_mm(&mut self) {
+    fn enable_mm(&mut self) {
         let read_config = TransferConfig {
-            iwidth: XspiWidth::OCTO,
-            isize: AddressSize::_16bit, // 2-byte command for OPI
-            adwidth: XspiWidth::OCTO,
+            iwidth: XspiWidth::SING,
+            isize: AddressSize::_8bit,
+            adwidth: XspiWidth::SING,
             adsize: AddressSize::_32bit,
-            dwidth: XspiWidth::OCTO,
-            instruction: Some(OpiCommand::OctaRead as u32),
-            dummy: DummyCycles::_20, // Default dummy cycles for OPI
+            dwidth: XspiWidth::SING,
+            instruction: Some(SpiCommand::FastRead4B as u32),
+            dummy: DummyCycles::_8,
             ..Default::default()
         };
 
         let write_config = TransferConfig {
-            iwidth: XspiWidth::OCTO,
-            isize: AddressSize::_16bit,
-            adwidth: XspiWidth::OCTO,
+            iwidth: XspiWidth::SING,
+            isize: AddressSize::_8bit,
+            adwidth: XspiWidth::SING,
             adsize: AddressSize::_32bit,
-            dwidth: XspiWidth::OCTO,
-            instruction: Some(OpiCommand::PageProgram4B as u32),
+            dwidth: XspiWidth::SING,
+            instruction: Some(SpiCommand::PageProgram4B as u32),
             dummy: DummyCycles::_0,
             ..Default::default()
         };
-
         self.xspi
             .enable_memory_mapped_mode(read_config, write_config)
             .unwrap();
+        mpu::set_mapped(true);
     }
+}
 
-    pub fn disable_mm(&mut self) {
-        self.xspi.disable_memory_mapped_mode();
+impl<I: Instance> OpiFlashMemory<I> {
+    pub fn into_spi(mut self) -> SpiFlashMemory<I> {
+        self.disable_opi_mode();
+        SpiFlashMemory { xspi: self.xspi }
+    }
+
+    /// Disable OPI mode and return to SPI
+    pub fn disable_opi_mode(&mut self) {
+        // Clear SOPI and DOPI bits in CR2 volatile register
+        let cr2_0 = self.read_cr2(0x00000000);
+        self.write_cr2(0x00000000, cr2_0 & 0xFC); // Clear bits 0 and 1
     }
 
     /// Execute OPI command (2-byte command)
@@ -879,11 +1022,13 @@ impl<I: Instance> OpiFlashMemory<I> {
     /// Erase 4KB sector using OPI
     pub fn erase_sector(&mut self, addr: u32) {
         self.perform_erase(addr, OpiCommand::SectorErase4B);
+        sync_caches(addr, MEMORY_SECTOR_SIZE);
     }
 
     /// Erase 64KB block using OPI
     pub fn erase_block_64k(&mut self, addr: u32) {
         self.perform_erase(addr, OpiCommand::BlockErase4B);
+        sync_caches(addr, MEMORY_BLOCK_SIZE);
     }
 
     /// Erase entire chip using OPI
@@ -891,6 +1036,7 @@ impl<I: Instance> OpiFlashMemory<I> {
         self.enable_write();
         self.exec_command(OpiCommand::ChipErase);
         self.wait_write_finish();
+        sync_caches(0, MEMORY_FLASH_BYTES as usize);
     }
 
     /// Write single page using OPI
@@ -933,6 +1079,7 @@ impl<I: Instance> OpiFlashMemory<I> {
             left -= chunk_size;
             chunk_start += chunk_size;
         }
+        sync_caches(addr, buffer.len());
     }
 
     /// Read register using OPI mode
@@ -1027,3 +1174,40 @@ impl<I: Instance> OpiFlashMemory<I> {
         self.wait_write_finish();
     }
 }
+
+impl<I: Instance> MemoryMap for OpiFlashMemory<I> {
+    /// Enable memory-mapped mode for OPI
+    fn enable_mm(&mut self) {
+        let read_config = TransferConfig {
+            iwidth: XspiWidth::OCTO,
+            isize: AddressSize::_16bit, // 2-byte command for OPI
+            adwidth: XspiWidth::OCTO,
+            adsize: AddressSize::_32bit,
+            dwidth: XspiWidth::OCTO,
+            instruction: Some(OpiCommand::OctaRead as u32),
+            dummy: DummyCycles::_20, // Default dummy cycles for OPI
+            ..Default::default()
+        };
+
+        let write_config = TransferConfig {
+            iwidth: XspiWidth::OCTO,
+            isize: AddressSize::_16bit,
+            adwidth: XspiWidth::OCTO,
+            adsize: AddressSize::_32bit,
+            dwidth: XspiWidth::OCTO,
+            instruction: Some(OpiCommand::PageProgram4B as u32),
+            dummy: DummyCycles::_0,
+            ..Default::default()
+        };
+
+        self.xspi
+            .enable_memory_mapped_mode(read_config, write_config)
+            .unwrap();
+        mpu::set_mapped(true);
+    }
+
+    fn disable_mm(&mut self) {
+        mpu::set_mapped(false);
+        self.xspi.disable_memory_mapped_mode();
+    }
+}
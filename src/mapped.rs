@@ -0,0 +1,58 @@
+// Safe access to the flash through the XSPI2 memory-mapped window.
+//
+// While the XSPI is in memory-mapped mode, no indirect commands may be issued
+// to the flash. The `MappedFlash` guard enforces this by mutably borrowing the
+// flash driver for as long as the window is in use, and switching back to
+// indirect mode when it is dropped.
+
+use core::ops::Deref;
+
+/// Start of the memory-mapped window of XSPI2, to which the flash is connected.
+pub const XSPI2_MAPPED_BASE: usize = 0x7000_0000;
+
+/// Size of the flash, and so of the part of the window that can be read.
+pub const MAPPED_SIZE: usize = 32 * 1024 * 1024;
+
+/// A flash driver that can switch its XSPI in and out of memory-mapped mode.
+pub trait MemoryMap: Sized {
+    /// Put the XSPI in memory-mapped mode.
+    /// Prefer `map()`, which guarantees indirect mode is restored afterwards.
+    fn enable_mm(&mut self);
+
+    /// Put the XSPI back in indirect mode.
+    fn disable_mm(&mut self);
+
+    /// Enter memory-mapped mode for as long as the returned guard lives.
+    fn map(&mut self) -> MappedFlash<'_, Self> {
+        MappedFlash::new(self)
+    }
+}
+
+/// The flash contents, readable as a byte slice while memory-mapped mode is on.
+pub struct MappedFlash<'a, F: MemoryMap> {
+    flash: &'a mut F,
+}
+
+impl<'a, F: MemoryMap> MappedFlash<'a, F> {
+    fn new(flash: &'a mut F) -> Self {
+        flash.enable_mm();
+        Self { flash }
+    }
+}
+
+impl<F: MemoryMap> Deref for MappedFlash<'_, F> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: memory-mapped mode stays enabled for the lifetime of the
+        // guard, and the driver (the only other way to modify the flash) is
+        // mutably borrowed by it.
+        unsafe { core::slice::from_raw_parts(XSPI2_MAPPED_BASE as *const u8, MAPPED_SIZE) }
+    }
+}
+
+impl<F: MemoryMap> Drop for MappedFlash<'_, F> {
+    fn drop(&mut self) {
+        self.flash.disable_mm();
+    }
+}
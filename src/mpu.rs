@@ -0,0 +1,76 @@
+// MPU configuration for the XSPI2 memory-mapped window.
+//
+// By default, the Cortex-M7 treats 0x7000_0000 as normal, executable memory,
+// so the CPU may speculatively read from it at any time. If the XSPI is not
+// in memory-mapped mode, such an access hangs the bus or causes a hardfault.
+// The window is therefore made strongly-ordered and inaccessible, except while
+// memory-mapped mode is on, when it is cacheable and executable.
+
+use cortex_m::asm;
+
+use crate::mapped::{MAPPED_SIZE, XSPI2_MAPPED_BASE};
+
+/// MPU region covering the whole 256 MiB XSPI2 window, always inaccessible.
+const REGION_WINDOW: u32 = 0;
+/// MPU region covering the flash device, overriding the above while mapped.
+pub(crate) const REGION_FLASH: u32 = 1;
+
+/// Region size encoding: size = 2 ^ (SIZE + 1) bytes.
+const fn rasr_size(size: usize) -> u32 {
+    (size.trailing_zeros() - 1) << 1
+}
+
+const WINDOW_SIZE: usize = 256 * 1024 * 1024;
+
+const RASR_ENABLE: u32 = 1 << 0;
+const RASR_XN: u32 = 1 << 28;
+const RASR_AP_NO_ACCESS: u32 = 0b000 << 24;
+const RASR_AP_READ_ONLY: u32 = 0b110 << 24;
+/// TEX = 000, C = 0, B = 0: strongly-ordered.
+const RASR_STRONGLY_ORDERED: u32 = 0;
+/// TEX = 000, C = 1, B = 0: normal memory, write-through, no write-allocate.
+const RASR_NORMAL_WT: u32 = 1 << 17;
+
+const CTRL_ENABLE: u32 = 1 << 0;
+const CTRL_PRIVDEFENA: u32 = 1 << 2;
+
+/// Set up the window regions and enable the MPU, with the window unmapped.
+/// All other memory keeps its default attributes.
+pub fn init() {
+    // SAFETY: only the regions reserved for the XSPI2 window are touched.
+    let mpu = unsafe { cortex_m::Peripherals::steal() }.MPU;
+    unsafe {
+        mpu.ctrl.write(0);
+        mpu.rnr.write(REGION_WINDOW);
+        mpu.rbar.write(XSPI2_MAPPED_BASE as u32);
+        mpu.rasr.write(
+            RASR_XN
+                | RASR_AP_NO_ACCESS
+                | RASR_STRONGLY_ORDERED
+                | rasr_size(WINDOW_SIZE)
+                | RASR_ENABLE,
+        );
+        mpu.ctrl.write(CTRL_ENABLE | CTRL_PRIVDEFENA);
+    }
+    set_mapped(false);
+}
+
+/// Switch the flash region between accessible (memory-mapped mode on) and
+/// inaccessible (memory-mapped mode off).
+pub fn set_mapped(mapped: bool) {
+    // SAFETY: only the regions reserved for the XSPI2 window are touched.
+    let mpu = unsafe { cortex_m::Peripherals::steal() }.MPU;
+    asm::dsb();
+    unsafe {
+        mpu.rnr.write(REGION_FLASH);
+        mpu.rbar.write(XSPI2_MAPPED_BASE as u32);
+        if mapped {
+            mpu.rasr
+                .write(RASR_AP_READ_ONLY | RASR_NORMAL_WT | rasr_size(MAPPED_SIZE) | RASR_ENABLE);
+        } else {
+            mpu.rasr.write(0);
+        }
+    }
+    asm::dsb();
+    asm::isb();
+}
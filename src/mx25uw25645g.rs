@@ -11,12 +11,26 @@
 // TODO: Can I move into OPI mode sooner, with less of the SPI stuff???
 
 use core::cmp::min;
+use core::future::poll_fn;
+use core::ops::Range;
+use core::task::Poll;
+use defmt::info;
+use embassy_stm32::interrupt::typelevel::{Binding, Handler, Interrupt, XSPI2};
 use embassy_stm32::mode::Blocking;
+use embassy_stm32::pac;
+use embassy_stm32::peripherals;
 use embassy_stm32::xspi::{
-    AddressSize, DummyCycles, Instance, MemorySize, MemoryType, TransferConfig, Xspi, XspiWidth,
+    AddressSize, DummyCycles, MemorySize, MemoryType, TransferConfig, Xspi, XspiWidth,
 };
+use embassy_sync::waitqueue::AtomicWaker;
+use embassy_time::{Duration, Timer};
 
-use crate::info;
+use crate::cache::{self, CacheCoherency};
+use crate::checksum::Checksum;
+use crate::erase_plan::{EraseError, EraseOp, ErasePlan};
+use crate::mapped::{MemoryMap, XSPI2_MAPPED_BASE};
+use crate::mpu;
+use crate::progress::{CancelToken, Cancelled, NoProgress, ProgressSink, Tracker};
 
 /// Settings for the Macronix MX25UW25645G.
 /// The MX25UW25645G has a program command page buffer size of 256 bytes.
@@ -26,16 +40,60 @@ use crate::info;
 const MEMORY_TYPE: MemoryType = MemoryType::Macronix;
 const DRIVE_STRENGTH: OutputDriveStrength = OutputDriveStrength::R24;
 const MEMORY_FLASH_SIZE: MemorySize = MemorySize::_32MiB; // 256 megabits = 32 megabytes.
-const MEMORY_BLOCK_SIZE: usize = 64 * 1024; // 512  blocks  of 64 Kbytes.
-const MEMORY_SECTOR_SIZE: usize = 4 * 1024; // 8192 sectors of  4 Kbytes.
+pub const MEMORY_FLASH_BYTES: u32 = 32 * 1024 * 1024;
+pub const MEMORY_BLOCK_SIZE: usize = 64 * 1024; // 512  blocks  of 64 Kbytes.
+pub const MEMORY_SECTOR_SIZE: usize = 4 * 1024; // 8192 sectors of  4 Kbytes.
 const MEMORY_PAGE_SIZE: usize = 256; // 131072 pages of 256 bytes.
+pub const SECURED_OTP_SIZE: usize = 1024; // 8K-bit secured OTP area.
 
 const DUMMY_CYCLES_READ: DummyCycles = DummyCycles::_8;
 const DUMMY_CYCLES_READ_OCTAL: DummyCycles = DummyCycles::_6;
-const DUMMY_CYCLES_READ_OCTAL_DTR: DummyCycles = DummyCycles::_6;
+const DUMMY_CYCLES_READ_OCTAL_DTR: DummyCycles = DummyCycles::_20; // Must match CR2_DUMMY_CYCLES.
 const DUMMY_CYCLES_REG_OCTAL: DummyCycles = DummyCycles::_4;
 const DUMMY_CYCLES_REG_OCTAL_DTR: DummyCycles = DummyCycles::_4;
 
+/// Configuration Register 2 address of the dummy cycle setting, DC[2:0].
+const CR2_DUMMY_CYCLES_ADDR: u32 = 0x0000_0300;
+const CR2_DUMMY_CYCLES_MASK: u8 = 0x07;
+/// DC[2:0] = 000: 20 dummy cycles, the reset default, valid up to 200 MHz.
+const CR2_DUMMY_CYCLES: u8 = 0b000;
+
+/// Memory-mapped mode chip-select timeout, in XSPI kernel clock cycles.
+/// After this much idle time, the flash is deselected and stops prefetching.
+const MM_TIMEOUT_CYCLES: u16 = 0x100;
+
+/// Status register Write In Progress bit.
+const STATUS_WIP: u8 = 0x01;
+
+/// Security register Program Suspend and Erase Suspend bits.
+const SECURITY_PSB: u8 = 0x04;
+const SECURITY_ESB: u8 = 0x08;
+
+/// Interval between two automatic status register reads, in XSPI bus clock
+/// cycles. At 150 MHz, this polls about every 1.7 us, which is short compared
+/// to the 0.15 ms page program time.
+const AUTO_POLL_INTERVAL_CYCLES: u16 = 0x100;
+
+/// Typical program and erase times from the datasheet, used to size the
+/// sleeps of the async operations when auto-polling is not enabled.
+const TYPICAL_PAGE_PROGRAM: Duration = Duration::from_micros(150);
+const TYPICAL_SECTOR_ERASE: Duration = Duration::from_millis(25);
+const TYPICAL_BLOCK_ERASE_64K: Duration = Duration::from_millis(220);
+const TYPICAL_CHIP_ERASE: Duration = Duration::from_secs(150);
+
+/// After the typical time has passed, WIP is polled this many times per
+/// typical time, but no more often than every `MIN_POLL_INTERVAL`.
+const POLLS_PER_TYPICAL_TIME: u32 = 10;
+const MIN_POLL_INTERVAL: Duration = Duration::from_micros(20);
+
+/// CR functional mode field. Written as raw bits, as the PAC only provides a
+/// typed setter for the other fields used here.
+const CR_FMODE_MASK: u32 = 0b11 << 28;
+const CR_FMODE_AUTO_POLLING: u32 = 0b10 << 28;
+
+/// Woken by the XSPI2 interrupt when automatic status polling matches.
+static STATUS_MATCH_WAKER: AtomicWaker = AtomicWaker::new();
+
 /// SPI mode commands for the MX25UW25645G flash memory.
 /// These are only used internally, to reset the chip and configure it into
 /// Octo-SPI mode.
@@ -183,14 +241,94 @@ pub enum OutputDriveStrength {
 }
 
 /// Access the Macronix MX25UW25645GXDI00 flash chip using Octo SPI.
-pub struct OpiFlashMemory<I: Instance> {
-    xspi: Xspi<'static, I, Blocking>,
+///
+/// The flash must be on XSPI2: besides the HAL, the driver uses the XSPI2
+/// registers and interrupt directly, and its memory-mapped window.
+pub struct OpiFlashMemory {
+    xspi: Xspi<'static, peripherals::XSPI2, Blocking>,
+    verify_on_write: bool,
+    cache_coherency: CacheCoherency,
+    window_used: bool,
+    auto_polling: bool,
+}
+
+/// XSPI2 interrupt handler, waking the task waiting for a program or erase
+/// to finish. Bind it with `bind_interrupts!` and pass the binding to
+/// `OpiFlashMemory::enable_auto_polling()`.
+pub struct StatusMatchInterruptHandler;
+
+impl Handler<XSPI2> for StatusMatchInterruptHandler {
+    unsafe fn on_interrupt() {
+        let regs = pac::XSPI2;
+        if regs.sr().read().smf() {
+            // Leave the flag for the waiting task, but stop the interrupt
+            // from firing again until it is cleared.
+            regs.cr().modify(|w| w.set_smie(false));
+            STATUS_MATCH_WAKER.wake();
+        }
+    }
+}
+
+/// Result of a failed verification: where the flash contents first differ
+/// from the expected data (relative to the start address), and in how many
+/// bytes in total.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Mismatch {
+    pub first_offset: usize,
+    pub count: usize,
+}
+
+/// A program or erase that was started, but not waited for yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct PendingOp {
+    addr: u32,
+    len: usize,
+    typical: Duration,
+}
+
+impl PendingOp {
+    /// Whether the operation changes any byte of `addr..addr + len`.
+    pub fn overlaps(&self, addr: u32, len: usize) -> bool {
+        addr < self.addr + self.len as u32 && self.addr < addr + len as u32
+    }
+}
+
+/// Failure of a tracked (cancellable) operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FlashError {
+    Erase(EraseError),
+    Mismatch(Mismatch),
+    Cancelled(Cancelled),
+}
+
+impl From<EraseError> for FlashError {
+    fn from(e: EraseError) -> Self {
+        Self::Erase(e)
+    }
+}
+
+impl From<Mismatch> for FlashError {
+    fn from(e: Mismatch) -> Self {
+        Self::Mismatch(e)
+    }
+}
+
+impl From<Cancelled> for FlashError {
+    fn from(e: Cancelled) -> Self {
+        Self::Cancelled(e)
+    }
 }
 
-impl<I: Instance> OpiFlashMemory<I> {
-    pub fn new(xspi: Xspi<'static, I, Blocking>) -> Self {
+impl OpiFlashMemory {
+    pub fn new(xspi: Xspi<'static, peripherals::XSPI2, Blocking>) -> Self {
         // Obtain a handle on the interface for the chip.
-        let mut memory = Self { xspi };
+        let mut memory = Self {
+            xspi,
+            verify_on_write: false,
+            cache_coherency: CacheCoherency::Invalidate,
+            window_used: false,
+            auto_polling: false,
+        };
 
         // Reset the memory before doing anything else.
         // This happens with the chip still in SPI mode
@@ -204,6 +342,15 @@ impl<I: Instance> OpiFlashMemory<I> {
         memory.write_cr2_spi(19, cr2_19 | 0x07);
         */
 
+        // Set the number of dummy cycles for octal DTR reads. All reads,
+        // including memory-mapped ones, must use this same number.
+        let cr2_300 = memory.read_cr2_spi(CR2_DUMMY_CYCLES_ADDR);
+        memory.exec_command_spi(SpiCommand::WriteEnable as u8);
+        memory.write_cr2_spi(
+            CR2_DUMMY_CYCLES_ADDR,
+            (cr2_300 & !CR2_DUMMY_CYCLES_MASK) | CR2_DUMMY_CYCLES,
+        );
+
         // Enable Octo-SPI in DTR mode.
         // Note: Do this as the last init step.
         let cr2_0 = memory.read_cr2_spi(0);
@@ -214,6 +361,12 @@ impl<I: Instance> OpiFlashMemory<I> {
         // Did that work???
         let cr2_0 = memory.read_cr2(0);
         info!("Read CR2 at 0x0 DTR: {:x}", cr2_0);
+        let cr2_300 = memory.read_cr2(CR2_DUMMY_CYCLES_ADDR);
+        assert_eq!(
+            cr2_300 & CR2_DUMMY_CYCLES_MASK,
+            CR2_DUMMY_CYCLES,
+            "Dummy cycle configuration was not accepted"
+        );
 
         /*
         // Set 24 Ohm drive strength.
@@ -237,6 +390,11 @@ impl<I: Instance> OpiFlashMemory<I> {
         memory
     }
 
+    /// Change the XSPI bus clock prescaler (bus clock = kernel / (prescaler + 1)).
+    pub fn set_clock_prescaler(&mut self, prescaler: u8) {
+        self.xspi.set_clock_prescaler(prescaler);
+    }
+
     fn reset_memory_spi(&mut self) {
         self.exec_command_spi(SpiCommand::ResetEnable as u8);
         self.exec_command_spi(SpiCommand::ResetMemory as u8);
@@ -313,46 +471,6 @@ impl<I: Instance> OpiFlashMemory<I> {
         self.wait_write_finish_spi();
     }
 
-    /// Enable memory-mapped mode for OPI
-    /// TODO
-    pub fn enable_mm(&mut self) {
-        let read_config = TransferConfig {
-            iwidth: XspiWidth::OCTO,
-            isize: AddressSize::_16bit, // 2-byte command for OPI
-            idtr: true,
-            adwidth: XspiWidth::OCTO,
-            adsize: AddressSize::_32bit,
-            addtr: true,
-            dwidth: XspiWidth::OCTO,
-            ddtr: true,
-            instruction: Some(OpiCommand::OctaDTRRead as u32),
-            dummy: DummyCycles::_20, // Default dummy cycles for OPI
-            ..Default::default()
-        };
-
-        let write_config = TransferConfig {
-            iwidth: XspiWidth::OCTO,
-            isize: AddressSize::_16bit,
-            idtr: true, // DTR mode.
-            adwidth: XspiWidth::OCTO,
-            adsize: AddressSize::_32bit,
-            addtr: true, // DTR mode.
-            dwidth: XspiWidth::OCTO,
-            ddtr: true, // DTR mode.
-            instruction: Some(OpiCommand::PageProgram4B as u32),
-            dummy: DummyCycles::_0,
-            ..Default::default()
-        };
-
-        self.xspi
-            .enable_memory_mapped_mode(read_config, write_config)
-            .unwrap();
-    }
-
-    pub fn disable_mm(&mut self) {
-        self.xspi.disable_memory_mapped_mode();
-    }
-
     /// Execute OPI command (2-byte command)
     /// TODO
     fn exec_command(&mut self, cmd: OpiCommand) {
@@ -411,7 +529,7 @@ impl<I: Instance> OpiFlashMemory<I> {
             ddtr: true,
             instruction: Some(OpiCommand::OctaDTRRead as u32),
             address: Some(addr),
-            dummy: DummyCycles::_20, // 20 Default for 200MHz operation
+            dummy: DUMMY_CYCLES_READ_OCTAL_DTR,
             ..Default::default()
         };
         self.xspi.blocking_read(buffer, transaction).unwrap();
@@ -419,12 +537,168 @@ impl<I: Instance> OpiFlashMemory<I> {
 
     /// Wait for write completion using OPI status read
     fn wait_write_finish(&mut self) {
-        while (self.read_sr() & 0x01) != 0 {}
+        while (self.read_sr() & STATUS_WIP) != 0 {}
     }
 
-    /// Perform erase operation using OPI command
-    /// TODO: OK
-    fn perform_erase(&mut self, addr: u32, cmd: OpiCommand) {
+    /// Let the async operations wait for completion with the XSPI automatic
+    /// status polling mode, instead of sleeping and reading the status
+    /// register.
+    /// The CPU is free (or asleep) until the XSPI2 interrupt signals the end
+    /// of the program or erase.
+    pub fn enable_auto_polling(&mut self, _irq: impl Binding<XSPI2, StatusMatchInterruptHandler>) {
+        XSPI2::unpend();
+        // SAFETY: the handler only touches the status match flag and enable.
+        unsafe { XSPI2::enable() };
+        self.auto_polling = true;
+    }
+
+    /// Wait for write completion without blocking the executor, for an
+    /// operation that typically takes `typical`.
+    ///
+    /// With auto-polling enabled, the XSPI polls the status register and
+    /// interrupts on completion. Otherwise, the task sleeps for the typical
+    /// time, then polls WIP with shorter sleeps.
+    async fn wait_write_finish_async(&mut self, typical: Duration) {
+        if self.auto_polling {
+            self.wait_status_match().await;
+            return;
+        }
+
+        Timer::after(typical).await;
+        let interval = (typical / POLLS_PER_TYPICAL_TIME).max(MIN_POLL_INTERVAL);
+        while (self.read_sr() & STATUS_WIP) != 0 {
+            Timer::after(interval).await;
+        }
+    }
+
+    /// Wait for write completion, with the XSPI polling the status register
+    /// until WIP reads 0.
+    ///
+    /// If the returned future is dropped early, the XSPI keeps polling until
+    /// the next wait or `suspend()` stops it. Other commands wait for the
+    /// polling to finish by itself, which happens when the operation does.
+    async fn wait_status_match(&mut self) {
+        self.stop_status_polling();
+
+        // The status read also leaves its instruction, address and data
+        // phases configured in the XSPI, to be reused for polling.
+        if self.read_sr() & STATUS_WIP == 0 {
+            return;
+        }
+
+        let regs = pac::XSPI2;
+        regs.fcr().write(|w| w.set_csmf(true));
+        regs.psmkr().write(|w| w.0 = STATUS_WIP as u32);
+        regs.psmar().write(|w| w.0 = 0);
+        regs.pir()
+            .write(|w| w.set_interval(AUTO_POLL_INTERVAL_CYCLES));
+        regs.cr().modify(|w| {
+            w.0 = (w.0 & !CR_FMODE_MASK) | CR_FMODE_AUTO_POLLING;
+            w.set_pmm(false);
+            w.set_apms(true); // Stop polling on the first match.
+            w.set_smie(true);
+        });
+        // Writing the address starts the polling.
+        regs.ar().write(|w| w.0 = 0);
+
+        poll_fn(|cx| {
+            STATUS_MATCH_WAKER.register(cx.waker());
+            if regs.sr().read().smf() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+
+        self.stop_status_polling();
+    }
+
+    /// Leave automatic status polling, aborting it if it is still running.
+    fn stop_status_polling(&mut self) {
+        let regs = pac::XSPI2;
+        if regs.cr().read().0 & CR_FMODE_MASK != CR_FMODE_AUTO_POLLING {
+            return;
+        }
+
+        regs.cr().modify(|w| w.set_abort(true));
+        while regs.cr().read().abort() {}
+        while regs.sr().read().busy() {}
+        regs.fcr().write(|w| w.set_csmf(true));
+        regs.cr().modify(|w| {
+            w.set_smie(false);
+            w.set_apms(false);
+            w.0 &= !CR_FMODE_MASK;
+        });
+    }
+
+    /// Suspend the program or erase in progress, so the flash can be read.
+    /// Reads from the sector being erased or programmed return undefined
+    /// data until the operation completes.
+    ///
+    /// Returns false if there was nothing to suspend, because the operation
+    /// already completed. Otherwise, `resume()` must follow.
+    pub fn suspend(&mut self) -> bool {
+        self.stop_status_polling();
+        if self.read_sr() & STATUS_WIP == 0 {
+            return false;
+        }
+
+        self.exec_command(OpiCommand::ProgramEraseSuspend);
+        // WIP clears once the suspend took effect, within the suspend latency.
+        self.wait_write_finish();
+        self.read_security() & (SECURITY_PSB | SECURITY_ESB) != 0
+    }
+
+    /// Resume the program or erase suspended by `suspend()`.
+    pub fn resume(&mut self) {
+        self.exec_command(OpiCommand::ProgramEraseResume);
+    }
+
+    /// Start one erase operation of a plan, without waiting for it.
+    pub fn start_erase_op(&mut self, op: EraseOp) -> PendingOp {
+        let (cmd, typical) = match op {
+            EraseOp::Sector(_) => (OpiCommand::SectorErase4B, TYPICAL_SECTOR_ERASE),
+            EraseOp::Block64k(_) => (OpiCommand::BlockErase4B, TYPICAL_BLOCK_ERASE_64K),
+        };
+        self.start_erase(op.addr(), cmd);
+        PendingOp {
+            addr: op.addr(),
+            len: op.size() as usize,
+            typical,
+        }
+    }
+
+    /// Start programming `data`, which must fit in one page, without waiting
+    /// for it.
+    pub fn start_program(&mut self, addr: u32, data: &[u8]) -> PendingOp {
+        self.start_page_program(addr, data, data.len());
+        PendingOp {
+            addr,
+            len: data.len(),
+            typical: TYPICAL_PAGE_PROGRAM,
+        }
+    }
+
+    /// Wait for an operation started with `start_erase_op()` or
+    /// `start_program()` to complete. Can be dropped and called again, e.g.
+    /// to suspend the operation in between.
+    pub async fn wait_pending(&mut self, op: PendingOp) {
+        self.wait_write_finish_async(op.typical).await;
+        self.sync_caches(op.addr, op.len);
+    }
+
+    /// In verify-on-write mode, compare the flash contents at `addr` with
+    /// `expected`. Otherwise, succeed without reading.
+    pub fn verify_written(&mut self, addr: u32, expected: &[u8]) -> Result<(), Mismatch> {
+        if self.verify_on_write {
+            self.verify(addr, expected)?;
+        }
+        Ok(())
+    }
+
+    /// Send the write enable and erase commands, without waiting.
+    fn start_erase(&mut self, addr: u32, cmd: OpiCommand) {
         let transaction = TransferConfig {
             iwidth: XspiWidth::OCTO,
             isize: AddressSize::_16bit,
@@ -441,6 +715,12 @@ impl<I: Instance> OpiFlashMemory<I> {
         };
         self.enable_write();
         self.xspi.blocking_command(&transaction).unwrap();
+    }
+
+    /// Perform erase operation using OPI command
+    /// TODO: OK
+    fn perform_erase(&mut self, addr: u32, cmd: OpiCommand) {
+        self.start_erase(addr, cmd);
         self.wait_write_finish();
     }
 
@@ -448,12 +728,14 @@ impl<I: Instance> OpiFlashMemory<I> {
     /// TODO: OK
     pub fn erase_sector(&mut self, addr: u32) {
         self.perform_erase(addr, OpiCommand::SectorErase4B);
+        self.sync_caches(addr, MEMORY_SECTOR_SIZE);
     }
 
     /// Erase 64KB block using OPI
     /// TODO: OK
     pub fn erase_block_64k(&mut self, addr: u32) {
         self.perform_erase(addr, OpiCommand::BlockErase4B);
+        self.sync_caches(addr, MEMORY_BLOCK_SIZE);
     }
 
     /// Erase entire chip using OPI
@@ -462,11 +744,160 @@ impl<I: Instance> OpiFlashMemory<I> {
         self.enable_write();
         self.exec_command(OpiCommand::ChipErase);
         self.wait_write_finish();
+        self.sync_caches(0, MEMORY_FLASH_BYTES as usize);
     }
 
-    /// Write single page using OPI
-    /// TODO
-    fn write_page(&mut self, addr: u32, buffer: &[u8], len: usize) {
+    /// Erase 4KB sector, waiting asynchronously.
+    pub async fn erase_sector_async(&mut self, addr: u32) {
+        self.start_erase(addr, OpiCommand::SectorErase4B);
+        self.wait_write_finish_async(TYPICAL_SECTOR_ERASE).await;
+        self.sync_caches(addr, MEMORY_SECTOR_SIZE);
+    }
+
+    /// Erase 64KB block, waiting asynchronously.
+    pub async fn erase_block_64k_async(&mut self, addr: u32) {
+        self.start_erase(addr, OpiCommand::BlockErase4B);
+        self.wait_write_finish_async(TYPICAL_BLOCK_ERASE_64K).await;
+        self.sync_caches(addr, MEMORY_BLOCK_SIZE);
+    }
+
+    /// Erase entire chip, waiting asynchronously.
+    pub async fn erase_chip_async(&mut self) {
+        self.enable_write();
+        self.exec_command(OpiCommand::ChipErase);
+        self.wait_write_finish_async(TYPICAL_CHIP_ERASE).await;
+        self.sync_caches(0, MEMORY_FLASH_BYTES as usize);
+    }
+
+    /// Erase the sector-aligned range `start..end`, using 64K block erases
+    /// where possible and 4K sector erases for the edges.
+    /// With `skip_blank`, areas that already read as erased are left alone.
+    /// Returns the executed plan.
+    pub fn erase_range(
+        &mut self,
+        start: u32,
+        end: u32,
+        skip_blank: bool,
+    ) -> Result<ErasePlan, EraseError> {
+        let plan = ErasePlan::new(start, end, MEMORY_FLASH_BYTES, skip_blank)?;
+        self.execute_erase_plan(&plan);
+        Ok(plan)
+    }
+
+    /// Execute a previously built (and possibly inspected) erase plan.
+    pub fn execute_erase_plan(&mut self, plan: &ErasePlan) {
+        for op in plan.ops() {
+            if plan.skip_blank() && self.is_blank(op.addr()..op.addr() + op.size()) {
+                continue;
+            }
+            match op {
+                EraseOp::Sector(addr) => self.erase_sector(addr),
+                EraseOp::Block64k(addr) => self.erase_block_64k(addr),
+            }
+        }
+    }
+
+    /// Erase the sector-aligned range `start..end` like `erase_range()`,
+    /// waiting asynchronously, reporting progress to `progress` and stopping
+    /// between two erase commands if `cancel` is triggered.
+    pub async fn erase_range_tracked(
+        &mut self,
+        start: u32,
+        end: u32,
+        skip_blank: bool,
+        progress: &mut impl ProgressSink,
+        cancel: &CancelToken,
+    ) -> Result<ErasePlan, FlashError> {
+        let plan = ErasePlan::new(start, end, MEMORY_FLASH_BYTES, skip_blank)?;
+        let mut tracker = Tracker::new(progress, cancel, plan.len());
+        for op in plan.ops() {
+            tracker.check()?;
+            if !(plan.skip_blank() && self.is_blank(op.addr()..op.addr() + op.size())) {
+                match op {
+                    EraseOp::Sector(addr) => self.erase_sector_async(addr).await,
+                    EraseOp::Block64k(addr) => self.erase_block_64k_async(addr).await,
+                }
+            }
+            tracker.advance(op.size());
+        }
+        Ok(plan)
+    }
+
+    /// Erase the entire chip with progress and cancellation.
+    ///
+    /// The chip erase command cannot report progress nor be stopped, so this
+    /// erases block by block instead, which takes about as long.
+    pub async fn erase_chip_tracked(
+        &mut self,
+        progress: &mut impl ProgressSink,
+        cancel: &CancelToken,
+    ) -> Result<(), FlashError> {
+        self.erase_range_tracked(0, MEMORY_FLASH_BYTES, false, progress, cancel)
+            .await?;
+        Ok(())
+    }
+
+    /// Check if `range` reads as fully erased (all 0xFF).
+    /// Uses memory-mapped reads for speed.
+    pub fn is_blank(&mut self, range: Range<u32>) -> bool {
+        let flash = self.map();
+        flash[range.start as usize..range.end as usize]
+            .iter()
+            .all(|&b| b == 0xFF)
+    }
+
+    /// Compare the flash contents at `addr` with `expected`.
+    /// Uses memory-mapped reads for speed.
+    pub fn verify(&mut self, addr: u32, expected: &[u8]) -> Result<(), Mismatch> {
+        let flash = self.map();
+        let actual = &flash[addr as usize..addr as usize + expected.len()];
+        let mut first = None;
+        let mut count = 0;
+        for (offset, (a, e)) in actual.iter().zip(expected).enumerate() {
+            if a != e {
+                first.get_or_insert(offset);
+                count += 1;
+            }
+        }
+
+        match first {
+            None => Ok(()),
+            Some(first_offset) => Err(Mismatch {
+                first_offset,
+                count,
+            }),
+        }
+    }
+
+    /// Calculate a checksum over `range`, streamed through memory-mapped reads.
+    pub fn crc32(&mut self, range: Range<u32>, checksum: &mut impl Checksum) -> u32 {
+        let flash = self.map();
+        checksum.reset();
+        checksum.update(&flash[range.start as usize..range.end as usize]);
+        checksum.finish()
+    }
+
+    /// When enabled, every write is read back and compared afterwards.
+    pub fn set_verify_on_write(&mut self, enable: bool) {
+        self.verify_on_write = enable;
+    }
+
+    /// Select how the CPU caches are kept coherent with the memory-mapped
+    /// window after program and erase. Defaults to invalidation.
+    pub fn set_cache_coherency(&mut self, mode: CacheCoherency) {
+        self.cache_coherency = mode;
+    }
+
+    /// Drop stale cache lines of the memory-mapped window after `addr..addr + len`
+    /// was modified. Not needed as long as the window was never read.
+    fn sync_caches(&mut self, addr: u32, len: usize) {
+        if self.window_used {
+            cache::sync_range(XSPI2_MAPPED_BASE + addr as usize, len, self.cache_coherency);
+        }
+    }
+
+    /// Send the write enable and page program commands, without waiting.
+    fn start_page_program(&mut self, addr: u32, buffer: &[u8], len: usize) {
         assert!(
             (len as u32 + (addr & 0x000000ff)) <= MEMORY_PAGE_SIZE as u32,
             "write_page(): page write length exceeds page boundary (len = {}, addr = {:X})",
@@ -490,12 +921,27 @@ impl<I: Instance> OpiFlashMemory<I> {
         };
         self.enable_write();
         self.xspi.blocking_write(buffer, transaction).unwrap();
+    }
+
+    /// Write single page using OPI
+    /// TODO
+    fn write_page(&mut self, addr: u32, buffer: &[u8], len: usize) {
+        self.start_page_program(addr, buffer, len);
         self.wait_write_finish();
+        self.sync_caches(addr, len);
+    }
+
+    /// Write single page, waiting asynchronously.
+    async fn write_page_async(&mut self, addr: u32, buffer: &[u8], len: usize) {
+        self.start_page_program(addr, buffer, len);
+        self.wait_write_finish_async(TYPICAL_PAGE_PROGRAM).await;
+        self.sync_caches(addr, len);
     }
 
     /// Write memory using OPI (handles page boundaries)
+    /// In verify-on-write mode, a mismatch after programming is reported.
     /// TODO
-    pub fn write_memory(&mut self, addr: u32, buffer: &[u8]) {
+    pub fn write_memory(&mut self, addr: u32, buffer: &[u8]) -> Result<(), Mismatch> {
         let mut left = buffer.len();
         let mut place = addr;
         let mut chunk_start = 0;
@@ -509,6 +955,121 @@ impl<I: Instance> OpiFlashMemory<I> {
             left -= chunk_size;
             chunk_start += chunk_size;
         }
+
+        if self.verify_on_write {
+            self.verify(addr, buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Write memory like `write_memory()`, waiting asynchronously for each page.
+    pub async fn write_memory_async(&mut self, addr: u32, buffer: &[u8]) -> Result<(), Mismatch> {
+        match self
+            .write_memory_tracked(addr, buffer, &mut NoProgress, &CancelToken::new())
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(FlashError::Mismatch(mismatch)) => Err(mismatch),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Write memory like `write_memory_async()`, reporting progress to
+    /// `progress` and stopping between two pages if `cancel` is triggered.
+    /// On cancellation, the pages before `Cancelled::done` have been written.
+    pub async fn write_memory_tracked(
+        &mut self,
+        addr: u32,
+        buffer: &[u8],
+        progress: &mut impl ProgressSink,
+        cancel: &CancelToken,
+    ) -> Result<(), FlashError> {
+        let mut tracker = Tracker::new(progress, cancel, buffer.len() as u32);
+        let mut left = buffer.len();
+        let mut place = addr;
+        let mut chunk_start = 0;
+
+        while left > 0 {
+            tracker.check()?;
+            let max_chunk_size = MEMORY_PAGE_SIZE - (place & 0x000000ff) as usize;
+            let chunk_size = min(max_chunk_size, left);
+            let chunk = &buffer[chunk_start..(chunk_start + chunk_size)];
+            self.write_page_async(place, chunk, chunk_size).await;
+            place += chunk_size as u32;
+            left -= chunk_size;
+            chunk_start += chunk_size;
+            tracker.advance(chunk_size as u32);
+        }
+
+        if self.verify_on_write {
+            self.verify(addr, buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Read-modify-write `data` at `addr`, with byte granularity.
+    ///
+    /// Each affected 4K sector is read into `sector_buf` and merged with the
+    /// new bytes. The sector is only erased if some bit has to go from 0 to 1,
+    /// and only the pages that differ from the flash contents are programmed.
+    /// In verify-on-write mode, a mismatch after programming is reported.
+    pub fn write_rmw(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+        sector_buf: &mut [u8; MEMORY_SECTOR_SIZE],
+    ) -> Result<(), Mismatch> {
+        let mut left = data.len();
+        let mut place = addr;
+        let mut data_start = 0;
+
+        while left > 0 {
+            let sector_addr = place & !(MEMORY_SECTOR_SIZE as u32 - 1);
+            let offset = (place - sector_addr) as usize;
+            let chunk_size = min(MEMORY_SECTOR_SIZE - offset, left);
+            let chunk = &data[data_start..(data_start + chunk_size)];
+
+            self.read_memory(sector_addr, sector_buf);
+
+            // Find out which pages change, and if any bit needs to go 0 -> 1.
+            let mut needs_erase = false;
+            let mut dirty_pages: u16 = 0;
+            for (i, &new) in chunk.iter().enumerate() {
+                let old = sector_buf[offset + i];
+                if old != new {
+                    dirty_pages |= 1 << ((offset + i) / MEMORY_PAGE_SIZE);
+                    needs_erase |= (!old & new) != 0;
+                }
+            }
+            sector_buf[offset..(offset + chunk_size)].copy_from_slice(chunk);
+
+            if needs_erase {
+                // Everything that is not blank must be reprogrammed after the erase.
+                self.erase_sector(sector_addr);
+                dirty_pages = 0;
+                for (page, contents) in sector_buf.chunks(MEMORY_PAGE_SIZE).enumerate() {
+                    if contents.iter().any(|&b| b != 0xFF) {
+                        dirty_pages |= 1 << page;
+                    }
+                }
+            }
+
+            for (page, contents) in sector_buf.chunks(MEMORY_PAGE_SIZE).enumerate() {
+                if dirty_pages & (1 << page) != 0 {
+                    let page_addr = sector_addr + (page * MEMORY_PAGE_SIZE) as u32;
+                    self.write_page(page_addr, contents, MEMORY_PAGE_SIZE);
+                }
+            }
+
+            place += chunk_size as u32;
+            left -= chunk_size;
+            data_start += chunk_size;
+        }
+
+        if self.verify_on_write {
+            self.verify(addr, data)?;
+        }
+        Ok(())
     }
 
     /// Read register using OPI mode
@@ -543,6 +1104,41 @@ impl<I: Instance> OpiFlashMemory<I> {
         )
     }
 
+    /// Read Security Register using OPI
+    pub fn read_security(&mut self) -> u8 {
+        self.read_register(
+            OpiCommand::ReadSecurityRegister,
+            0x00000000, // Dummy address
+            DummyCycles::_4,
+        )
+    }
+
+    /// Read `buffer.len()` bytes of the secured OTP area, from `addr` within
+    /// it.
+    pub fn read_otp(&mut self, addr: u32, buffer: &mut [u8]) {
+        assert!(addr as usize + buffer.len() <= SECURED_OTP_SIZE);
+        self.exec_command(OpiCommand::EnterSecuredOTP);
+        self.read_memory(addr, buffer);
+        self.exec_command(OpiCommand::ExitSecuredOTP);
+    }
+
+    /// Program `data` into the secured OTP area at `addr` (handles page
+    /// boundaries). The area cannot be erased: programmed bits stay 0.
+    pub fn program_otp(&mut self, addr: u32, data: &[u8]) {
+        assert!(addr as usize + data.len() <= SECURED_OTP_SIZE);
+        self.exec_command(OpiCommand::EnterSecuredOTP);
+        let mut place = addr;
+        let mut data = data;
+        while !data.is_empty() {
+            let chunk_size = min(MEMORY_PAGE_SIZE - (place & 0x000000ff) as usize, data.len());
+            self.start_page_program(place, &data[..chunk_size], chunk_size);
+            self.wait_write_finish();
+            place += chunk_size as u32;
+            data = &data[chunk_size..];
+        }
+        self.exec_command(OpiCommand::ExitSecuredOTP);
+    }
+
     /// Read Configuration Register using OPI
     /// TODO
     pub fn read_cr(&mut self) -> u8 {
@@ -629,3 +1225,74 @@ impl<I: Instance> OpiFlashMemory<I> {
         self.wait_write_finish();
     }
 }
+
+impl MemoryMap for OpiFlashMemory {
+    /// Enable memory-mapped mode for octal DTR, suitable for XIP.
+    fn enable_mm(&mut self) {
+        let read_config = TransferConfig {
+            iwidth: XspiWidth::OCTO,
+            isize: AddressSize::_16bit, // 2-byte command for OPI
+            idtr: true,
+            adwidth: XspiWidth::OCTO,
+            adsize: AddressSize::_32bit,
+            addtr: true,
+            dwidth: XspiWidth::OCTO,
+            ddtr: true,
+            instruction: Some(OpiCommand::OctaDTRRead as u32),
+            dummy: DUMMY_CYCLES_READ_OCTAL_DTR,
+            ..Default::default()
+        };
+
+        let write_config = TransferConfig {
+            iwidth: XspiWidth::OCTO,
+            isize: AddressSize::_16bit,
+            idtr: true, // DTR mode.
+            adwidth: XspiWidth::OCTO,
+            adsize: AddressSize::_32bit,
+            addtr: true, // DTR mode.
+            dwidth: XspiWidth::OCTO,
+            ddtr: true, // DTR mode.
+            instruction: Some(OpiCommand::PageProgram4B as u32),
+            dummy: DummyCycles::_0,
+            ..Default::default()
+        };
+
+        self.xspi
+            .enable_memory_mapped_mode(read_config, write_config)
+            .unwrap();
+        configure_mm_extras();
+        mpu::set_mapped(true);
+        self.window_used = true;
+    }
+
+    fn disable_mm(&mut self) {
+        mpu::set_mapped(false);
+        self.xspi.disable_memory_mapped_mode();
+    }
+}
+
+/// Memory-mapped mode settings not exposed by the HAL: DQS for reads, the
+/// chip-select timeout and prefetching.
+///
+/// The XSPI only accepts these while it is not busy, so the transfer started
+/// by the HAL when it entered memory-mapped mode is aborted first. FMODE is
+/// kept, so the next access to the window restarts with the new settings.
+/// The flash is connected to XSPI2.
+fn configure_mm_extras() {
+    let regs = pac::XSPI2;
+
+    regs.cr().modify(|w| w.set_abort(true));
+    while regs.cr().read().abort() {}
+    while regs.sr().read().busy() {}
+
+    // The flash drives DQS during octal DTR reads, use it to sample the data.
+    regs.ccr().modify(|w| w.set_dqse(true));
+
+    // Release chip select when idle, so the flash can return to standby, and
+    // keep automatic prefetching of the next sequential address enabled.
+    regs.lptr().write(|w| w.set_timeout(MM_TIMEOUT_CYCLES));
+    regs.cr().modify(|w| {
+        w.set_tcen(true);
+        w.set_nopref(false);
+    });
+}
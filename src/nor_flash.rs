@@ -0,0 +1,180 @@
+// `embedded-storage` NOR flash traits for the octal flash, as used by the
+// swap (see `swap`).
+//
+// The bootloader owns the driver and uses the blocking traits. Applications
+// share the flash through the flash service, so `FlashClient` implements the
+// async traits, to write updates to DFU and request their swap. Applications
+// executing in place from the flash cannot use the driver, and use
+// `XipFlash` instead, through `BlockingAsync` for the async traits.
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use embedded_storage_async::nor_flash as async_nor_flash;
+
+use crate::erase_plan::EraseError;
+use crate::flash_service::FlashClient;
+use crate::mx25uw25645g::{
+    FlashError, MEMORY_FLASH_BYTES, MEMORY_SECTOR_SIZE, Mismatch, OpiFlashMemory,
+};
+use crate::ram_flash::{self, XipFlash};
+
+/// Octal DTR transfers move two bytes per clock, so writes must start at an
+/// even address and have an even length.
+const WRITE_SIZE: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum StorageError {
+    NotAligned,
+    OutOfBounds,
+    Flash(FlashError),
+}
+
+impl NorFlashError for StorageError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::NotAligned => NorFlashErrorKind::NotAligned,
+            Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Self::Flash(_) => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl From<FlashError> for StorageError {
+    fn from(e: FlashError) -> Self {
+        Self::Flash(e)
+    }
+}
+
+impl From<EraseError> for StorageError {
+    fn from(e: EraseError) -> Self {
+        Self::Flash(e.into())
+    }
+}
+
+impl From<Mismatch> for StorageError {
+    fn from(e: Mismatch) -> Self {
+        Self::Flash(e.into())
+    }
+}
+
+/// Check that `from..to` lies within the flash, and is aligned to `align`.
+fn check_range(from: u32, to: u32, align: usize) -> Result<(), StorageError> {
+    if from > to || to > MEMORY_FLASH_BYTES {
+        return Err(StorageError::OutOfBounds);
+    }
+    if from as usize % align != 0 || to as usize % align != 0 {
+        return Err(StorageError::NotAligned);
+    }
+    Ok(())
+}
+
+/// End of an access of `len` bytes at `offset`, saturating on overflow.
+fn end(offset: u32, len: usize) -> u32 {
+    offset.saturating_add(len.try_into().unwrap_or(u32::MAX))
+}
+
+impl ErrorType for OpiFlashMemory {
+    type Error = StorageError;
+}
+
+impl ReadNorFlash for OpiFlashMemory {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), StorageError> {
+        check_range(offset, end(offset, bytes.len()), Self::READ_SIZE)?;
+        self.read_memory(offset, bytes);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        MEMORY_FLASH_BYTES as usize
+    }
+}
+
+impl NorFlash for OpiFlashMemory {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = MEMORY_SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), StorageError> {
+        check_range(from, to, Self::ERASE_SIZE)?;
+        self.erase_range(from, to, false)?;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), StorageError> {
+        check_range(offset, end(offset, bytes.len()), Self::WRITE_SIZE)?;
+        self.write_memory(offset, bytes)?;
+        Ok(())
+    }
+}
+
+impl ErrorType for FlashClient {
+    type Error = StorageError;
+}
+
+impl async_nor_flash::ReadNorFlash for FlashClient {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), StorageError> {
+        check_range(offset, end(offset, bytes.len()), Self::READ_SIZE)?;
+        FlashClient::read(self, offset, bytes).await;
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        MEMORY_FLASH_BYTES as usize
+    }
+}
+
+impl async_nor_flash::NorFlash for FlashClient {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = MEMORY_SECTOR_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), StorageError> {
+        check_range(from, to, Self::ERASE_SIZE)?;
+        FlashClient::erase(self, from, to).await?;
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), StorageError> {
+        check_range(offset, end(offset, bytes.len()), Self::WRITE_SIZE)?;
+        FlashClient::write(self, offset, bytes).await?;
+        Ok(())
+    }
+}
+
+impl ErrorType for XipFlash {
+    type Error = StorageError;
+}
+
+impl ReadNorFlash for XipFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), StorageError> {
+        check_range(offset, end(offset, bytes.len()), Self::READ_SIZE)?;
+        ram_flash::read(offset, bytes);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        MEMORY_FLASH_BYTES as usize
+    }
+}
+
+impl NorFlash for XipFlash {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = MEMORY_SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), StorageError> {
+        check_range(from, to, Self::ERASE_SIZE)?;
+        ram_flash::erase_range(from, to);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), StorageError> {
+        check_range(offset, end(offset, bytes.len()), Self::WRITE_SIZE)?;
+        ram_flash::program(offset, bytes);
+        Ok(())
+    }
+}
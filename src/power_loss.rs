@@ -0,0 +1,304 @@
+// Power-loss simulation of a complete firmware update, on a `SimFlash`.
+//
+// An update is the application erasing DFU, writing the new image there and
+// requesting the swap, followed by the bootloader swapping it in and booting
+// it on trial. It ends with the application confirming it, or with the
+// bootloader reverting it after too many boots, after a watchdog reset, or
+// because it refuses to start it.
+// `check_update()` cuts the power after each program or erase operation of
+// the update in turn, restarts the bootloader with the power cut after each
+// of its own operations, and finally lets it complete. ACTIVE must then hold
+// a valid image: the new one if swapped in and not reverted, otherwise the
+// old one.
+//
+// `check_update()` panics on failure. The tests below run it for every
+// ending, on the host (`cargo test-host`). It can also run on the target,
+// with enough stack for a few copies of the simulated flash. The partitions
+// are scaled down to keep it fast, but keep the page and sector sizes of the
+// octal flash.
+
+use core::cell::RefCell;
+use core::ops::Range;
+
+#[cfg(target_os = "none")]
+use defmt::panic;
+use embassy_embedded_hal::adapter::BlockingAsync;
+use embassy_embedded_hal::flash::partition::{BlockingPartition, Error};
+use embassy_futures::block_on;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::checksum::{CRC32_ISO_HDLC, Checksum, SoftwareCrc};
+use crate::image::{self, HEADER_SLOT_SIZE, ImageHeader};
+use crate::sim_flash::{SIM_PAGE_SIZE, SIM_SECTOR_SIZE, SimError, SimFlash};
+use crate::swap::{FirmwareUpdater, MAX_TRIAL_BOOTS, Partitions, State};
+
+const ACTIVE_SIZE: usize = 4 * SIM_SECTOR_SIZE;
+
+// Partitions in the simulated flash.
+const STATE: Range<usize> = 0..4 * SIM_SECTOR_SIZE;
+const ACTIVE: Range<usize> = STATE.end..STATE.end + ACTIVE_SIZE;
+const DFU: Range<usize> = ACTIVE.end..ACTIVE.end + ACTIVE_SIZE + SIM_SECTOR_SIZE;
+
+pub const SIM_SIZE: usize = DFU.end;
+
+/// Where ACTIVE would be mapped, for image validation.
+const ACTIVE_BASE: u32 = 0x7002_0000;
+
+type Flash = Mutex<NoopRawMutex, RefCell<SimFlash<SIM_SIZE>>>;
+
+/// How an update ends, once swapped in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum Ending {
+    /// The application confirms it on its first boot.
+    Confirmed,
+    /// The application never confirms it, and it is reverted after
+    /// `MAX_TRIAL_BOOTS` boots.
+    NotConfirmed,
+    /// The watchdog resets the device during its first boot, and it is
+    /// reverted.
+    Watchdog,
+    /// The bootloader refuses to start it on its first boot, and reverts it.
+    Rejected,
+}
+
+type PartitionError = Error<SimError>;
+
+fn partition(
+    flash: &Flash,
+    range: Range<usize>,
+) -> BlockingPartition<'_, NoopRawMutex, SimFlash<SIM_SIZE>> {
+    BlockingPartition::new(flash, range.start as u32, range.len() as u32)
+}
+
+/// A valid image with a payload of `payload_len` bytes, padded to the size
+/// of ACTIVE. Its second sector is erased, as the swap skips erased chunks.
+fn build_image(version: u32, payload_len: usize) -> [u8; ACTIVE_SIZE] {
+    let mut image = [0xFF; ACTIVE_SIZE];
+    let payload_range = HEADER_SLOT_SIZE..HEADER_SLOT_SIZE + payload_len;
+    for offset in payload_range.clone() {
+        if offset / SIM_SECTOR_SIZE != 1 {
+            image[offset] = (offset as u32 * 7 + version * 13) as u8;
+        }
+    }
+
+    // The reset vector of the payload's vector table.
+    let load_address = ACTIVE_BASE + HEADER_SLOT_SIZE as u32;
+    let entry_point = (load_address + 0x200) | 1;
+    image[HEADER_SLOT_SIZE + 4..HEADER_SLOT_SIZE + 8].copy_from_slice(&entry_point.to_le_bytes());
+
+    let mut crc = SoftwareCrc::new(CRC32_ISO_HDLC);
+    crc.update(&image[payload_range]);
+    let header = ImageHeader {
+        image_version: version,
+        load_address,
+        image_size: payload_len as u32,
+        entry_point,
+        flags: 0,
+        trailer_size: 0,
+        payload_crc: crc.finish(),
+        security_version: 0,
+    };
+    header.write(&mut image[..HEADER_SLOT_SIZE], &mut crc);
+    image
+}
+
+type Updater<'a> =
+    FirmwareUpdater<BlockingAsync<BlockingPartition<'a, NoopRawMutex, SimFlash<SIM_SIZE>>>>;
+
+/// The application's updater.
+fn updater(flash: &Flash) -> Updater<'_> {
+    FirmwareUpdater::new(
+        BlockingAsync::new(partition(flash, DFU)),
+        BlockingAsync::new(partition(flash, STATE)),
+    )
+}
+
+/// The application's part of an update: write `image` to DFU, and request
+/// its swap.
+fn write_update(flash: &Flash, image: &[u8]) -> Result<(), PartitionError> {
+    let mut updater = updater(flash);
+    block_on(async {
+        updater.prepare_update().await?;
+        updater.write_firmware(0, image).await?;
+        updater.mark_updated().await
+    })
+}
+
+/// The application's confirmation of the update.
+fn mark_booted(flash: &Flash) -> Result<(), PartitionError> {
+    block_on(updater(flash).mark_booted())
+}
+
+/// The bootloader's part: get ACTIVE ready to boot.
+fn boot(flash: &Flash, watchdog_reset: bool) -> Result<State, PartitionError> {
+    let mut partitions = Partitions::new(
+        partition(flash, ACTIVE),
+        partition(flash, DFU),
+        partition(flash, STATE),
+    );
+    // Smaller than a sector, to copy sectors in several chunks.
+    let mut buf = [0; SIM_PAGE_SIZE];
+    partitions.prepare_boot(&mut buf, watchdog_reset)
+}
+
+/// The bootloader rejecting the update booted on trial.
+fn revert(flash: &Flash) -> Result<(), PartitionError> {
+    let mut partitions = Partitions::new(
+        partition(flash, ACTIVE),
+        partition(flash, DFU),
+        partition(flash, STATE),
+    );
+    partitions.revert()
+}
+
+/// A complete update, from writing DFU to `ending`.
+fn update(flash: &Flash, image: &[u8], ending: Ending) -> Result<State, PartitionError> {
+    write_update(flash, image)?;
+    boot(flash, false)?;
+    match ending {
+        Ending::Confirmed => {
+            mark_booted(flash)?;
+            boot(flash, false)
+        }
+        Ending::NotConfirmed => {
+            for _ in 1..MAX_TRIAL_BOOTS {
+                boot(flash, false)?;
+            }
+            boot(flash, false)
+        }
+        Ending::Watchdog => boot(flash, true),
+        Ending::Rejected => {
+            revert(flash)?;
+            boot(flash, false)
+        }
+    }
+}
+
+/// Restore the power, then cut it again after `operations`, if any.
+fn set_power(flash: &Flash, operations: Option<usize>, torn: bool) {
+    flash.lock(|flash| {
+        let mut flash = flash.borrow_mut();
+        flash.restore_power();
+        if let Some(operations) = operations {
+            flash.cut_power_after(operations, torn);
+        }
+    });
+}
+
+/// Check that ACTIVE holds the image that `state` implies, and that it is
+/// valid. DFU must hold the other image: shifted by one sector after a swap.
+fn check_images(flash: &Flash, state: State, old: &[u8], new: &[u8]) {
+    flash.lock(|flash| {
+        let flash = flash.borrow();
+        let active = &flash.contents()[ACTIVE];
+        let dfu = &flash.contents()[DFU];
+        let expected = match state {
+            State::Boot => old,
+            State::Trial { .. } | State::Confirmed => {
+                assert!(
+                    &dfu[SIM_SECTOR_SIZE..] == old,
+                    "DFU does not hold the old image"
+                );
+                new
+            }
+            State::Reverted => {
+                assert!(
+                    &dfu[..ACTIVE_SIZE] == new,
+                    "DFU does not hold the new image"
+                );
+                old
+            }
+            State::Swap { .. } | State::Revert { .. } => {
+                panic!("Swap or revert not completed: {:?}", state)
+            }
+        };
+        assert!(
+            active == expected,
+            "ACTIVE does not hold the expected image"
+        );
+        let mut crc = SoftwareCrc::new(CRC32_ISO_HDLC);
+        if let Err(e) = image::validate(active, ACTIVE_BASE, &mut crc) {
+            panic!("Invalid image in ACTIVE: {:?}", e);
+        }
+    });
+}
+
+/// Simulate a power cut after every operation of an update ending with
+/// `ending`, and after every operation of the restart that follows. If
+/// `torn`, the interrupted operations take partial effect. Returns the number
+/// of scenarios checked, panics if one ends without a valid image in ACTIVE.
+pub fn check_update(ending: Ending, torn: bool) -> usize {
+    let old = build_image(1, 9000);
+    let new = build_image(2, 14000);
+    let mut installed = SimFlash::<SIM_SIZE>::new();
+    installed.write(ACTIVE.start as u32, &old).unwrap();
+
+    let mut scenarios = 0;
+    for first_cut in 0.. {
+        let updating = Flash::new(RefCell::new(installed.clone()));
+        set_power(&updating, Some(first_cut), torn);
+        let update_result = update(&updating, &new, ending);
+
+        for second_cut in 0.. {
+            let restarting = Flash::new(RefCell::new(updating.lock(|f| f.borrow().clone())));
+            set_power(&restarting, Some(second_cut), torn);
+            let restart_interrupted = boot(&restarting, false).is_err();
+
+            set_power(&restarting, None, torn);
+            let state = boot(&restarting, false).unwrap();
+            check_images(&restarting, state, &old, &new);
+            if let Ok(&ended) = update_result.as_ref() {
+                let expected = match ending {
+                    Ending::Confirmed => State::Confirmed,
+                    Ending::NotConfirmed | Ending::Watchdog | Ending::Rejected => State::Reverted,
+                };
+                assert_eq!(ended, expected);
+                assert_eq!(state, expected);
+            }
+            scenarios += 1;
+
+            if !restart_interrupted {
+                break;
+            }
+        }
+
+        if update_result.is_ok() {
+            return scenarios;
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(ending: Ending) {
+        for torn in [false, true] {
+            assert!(check_update(ending, torn) > 0);
+        }
+    }
+
+    #[test]
+    fn confirmed_update_survives_power_loss() {
+        check(Ending::Confirmed);
+    }
+
+    #[test]
+    fn unconfirmed_update_survives_power_loss() {
+        check(Ending::NotConfirmed);
+    }
+
+    #[test]
+    fn watchdog_reverted_update_survives_power_loss() {
+        check(Ending::Watchdog);
+    }
+
+    #[test]
+    fn rejected_update_survives_power_loss() {
+        check(Ending::Rejected);
+    }
+}
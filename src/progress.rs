@@ -0,0 +1,144 @@
+// Progress reporting and cancellation for long flash operations.
+//
+// A chip erase or a multi-megabyte write takes seconds to minutes. The
+// tracked driver operations report their progress to a `ProgressSink` (a
+// closure, or the sending end of a channel), and check a `CancelToken`
+// between two flash commands, which is the only point where they can stop
+// without leaving a command half done.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::channel::Sender;
+use embassy_time::{Duration, Instant};
+
+/// Progress is reported at most once per this many bytes, and at the end.
+const REPORT_GRANULARITY: u32 = 4 * 1024;
+
+/// State of a running operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Progress {
+    /// Bytes erased or written so far.
+    pub done: u32,
+    /// Bytes to erase or write in total.
+    pub total: u32,
+    /// Time since the operation started.
+    pub elapsed: Duration,
+}
+
+/// Receives progress reports.
+pub trait ProgressSink {
+    fn report(&mut self, progress: Progress);
+}
+
+impl<F: FnMut(Progress)> ProgressSink for F {
+    fn report(&mut self, progress: Progress) {
+        self(progress)
+    }
+}
+
+/// Reports are dropped while the channel is full, so a slow receiver never
+/// holds up the flash.
+impl<M: RawMutex, const N: usize> ProgressSink for Sender<'_, M, Progress, N> {
+    fn report(&mut self, progress: Progress) {
+        let _ = self.try_send(progress);
+    }
+}
+
+/// A sink ignoring all reports.
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn report(&mut self, _progress: Progress) {}
+}
+
+/// Requests an operation to stop at the next safe boundary.
+/// Typically a `static`, shared between the operation and whoever may cancel it.
+pub struct CancelToken {
+    cancelled: AtomicBool,
+}
+
+impl CancelToken {
+    pub const fn new() -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Clear a previous cancellation, so the token can be reused.
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An operation stopped on request, after completing `done` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Cancelled {
+    pub done: u32,
+}
+
+/// Bookkeeping of a running operation, shared by the tracked driver functions.
+pub struct Tracker<'a, P: ProgressSink> {
+    sink: &'a mut P,
+    cancel: &'a CancelToken,
+    start: Instant,
+    total: u32,
+    done: u32,
+    reported: u32,
+}
+
+impl<'a, P: ProgressSink> Tracker<'a, P> {
+    /// Start tracking an operation over `total` bytes, and report 0 done.
+    pub fn new(sink: &'a mut P, cancel: &'a CancelToken, total: u32) -> Self {
+        let mut tracker = Self {
+            sink,
+            cancel,
+            start: Instant::now(),
+            total,
+            done: 0,
+            reported: 0,
+        };
+        tracker.report();
+        tracker
+    }
+
+    /// Call before each flash command: fails if cancellation was requested.
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.cancel.is_cancelled() {
+            Err(Cancelled { done: self.done })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Record `bytes` more as done, reporting if enough progress was made.
+    pub fn advance(&mut self, bytes: u32) {
+        self.done += bytes;
+        if self.done - self.reported >= REPORT_GRANULARITY || self.done == self.total {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        self.reported = self.done;
+        self.sink.report(Progress {
+            done: self.done,
+            total: self.total,
+            elapsed: self.start.elapsed(),
+        });
+    }
+}
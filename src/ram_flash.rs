@@ -0,0 +1,430 @@
+// Flash program/erase routines that run from ITCM, for use by code that
+// executes in place from the external flash.
+//
+// While the flash is programmed or erased, it cannot be read, so memory-mapped
+// mode must be left and the CPU must not fetch a single instruction from the
+// external flash. The routines below are linked into the .itcm_text section
+// (see memory.x) and run with interrupts masked. Like `mpu::set_mapped(false)`,
+// they make the flash region inaccessible, so the CPU does not even read it
+// speculatively. On return, memory-mapped mode and the MPU region are
+// restored exactly as they were found, and the affected cache lines and the
+// branch predictor are invalidated.
+//
+// Nothing may be called that could live in the external flash, including
+// library code that is normally inlined. So every function used in between is
+// itself placed in .itcm_text, registers are accessed with inline assembly at
+// fixed addresses (checked against the PAC by `init()`), data is not sliced,
+// and arithmetic cannot panic. To check a build, disassemble the section,
+// e.g. with `llvm-objdump -d -j .itcm_text <elf>`: no branch may leave it.
+//
+// The flash must be in octal DTR mode, as set up by the mx25uw25645g driver.
+
+use core::ptr;
+
+use cortex_m::asm;
+use embassy_stm32::pac;
+
+use crate::mapped::XSPI2_MAPPED_BASE;
+use crate::mpu::REGION_FLASH;
+
+const PAGE_SIZE: usize = 256;
+const SECTOR_SIZE: u32 = 4 * 1024;
+
+// Octal DTR commands, see mx25uw25645g::OpiCommand.
+const CMD_WRITE_ENABLE: u32 = 0x06F9;
+const CMD_READ_STATUS: u32 = 0x05FA;
+const CMD_PAGE_PROGRAM: u32 = 0x12ED;
+const CMD_SECTOR_ERASE: u32 = 0x21DE;
+
+const STATUS_WIP: u32 = 0x01;
+const DUMMY_CYCLES_REG: u32 = 4;
+
+// XSPI2 registers.
+const XSPI2_BASE: u32 = 0x5202_A000;
+const XSPI_CR: u32 = XSPI2_BASE;
+const XSPI_SR: u32 = XSPI2_BASE + 0x020;
+const XSPI_FCR: u32 = XSPI2_BASE + 0x024;
+const XSPI_DLR: u32 = XSPI2_BASE + 0x040;
+const XSPI_AR: u32 = XSPI2_BASE + 0x048;
+const XSPI_DR: u32 = XSPI2_BASE + 0x050;
+const XSPI_CCR: u32 = XSPI2_BASE + 0x100;
+const XSPI_TCR: u32 = XSPI2_BASE + 0x108;
+const XSPI_IR: u32 = XSPI2_BASE + 0x110;
+
+// XSPI register fields.
+const CR_ABORT: u32 = 1 << 1;
+const CR_FMODE_MASK: u32 = 0b11 << 28;
+const CR_FMODE_WRITE: u32 = 0b00 << 28;
+const CR_FMODE_READ: u32 = 0b01 << 28;
+const TCR_DCYC_MASK: u32 = 0x1F;
+const SR_TCF: u32 = 1 << 1;
+const SR_BUSY: u32 = 1 << 5;
+const FCR_CTCF: u32 = 1 << 1;
+
+/// CCR for an octal DTR transfer: 16-bit instruction, optional 32-bit
+/// address and optional data, all on 8 lines.
+#[unsafe(link_section = ".itcm_text")]
+#[inline(always)]
+const fn ccr(address: bool, data: bool) -> u32 {
+    const OCTO: u32 = 0b100;
+    let mut ccr = OCTO | (1 << 3) | (0b01 << 4); // IMODE, IDTR, ISIZE
+    if address {
+        ccr |= (OCTO << 8) | (1 << 11) | (0b11 << 12); // ADMODE, ADDTR, ADSIZE
+    }
+    if data {
+        ccr |= (OCTO << 24) | (1 << 27); // DMODE, DDTR
+    }
+    ccr
+}
+
+// Cortex-M7 MPU and cache maintenance registers.
+const MPU_RNR: u32 = 0xE000_ED98;
+const MPU_RASR: u32 = 0xE000_EDA0;
+const ICIALLU: u32 = 0xE000_EF50;
+const DCIMVAC: u32 = 0xE000_EF5C;
+const BPIALL: u32 = 0xE000_EF78;
+const CACHE_LINE_SIZE: u32 = 32;
+
+/// PRIMASK.PM: set while interrupts are masked.
+const PRIMASK_PM: u32 = 1 << 0;
+
+// Bounds of the .itcm_text section, see memory.x.
+unsafe extern "C" {
+    static mut __sitcm_text: u32;
+    static mut __eitcm_text: u32;
+    static __sitcm_text_lma: u32;
+}
+
+/// Copy the routines to ITCM. Must be called once, before any other function
+/// of this module.
+pub fn init() {
+    let regs = pac::XSPI2;
+    let addresses = [
+        (regs.cr().as_ptr() as u32, XSPI_CR),
+        (regs.sr().as_ptr() as u32, XSPI_SR),
+        (regs.fcr().as_ptr() as u32, XSPI_FCR),
+        (regs.dlr().as_ptr() as u32, XSPI_DLR),
+        (regs.ar().as_ptr() as u32, XSPI_AR),
+        (regs.dr().as_ptr() as u32, XSPI_DR),
+        (regs.ccr().as_ptr() as u32, XSPI_CCR),
+        (regs.tcr().as_ptr() as u32, XSPI_TCR),
+        (regs.ir().as_ptr() as u32, XSPI_IR),
+    ];
+    for (pac_address, address) in addresses {
+        assert_eq!(pac_address, address, "Wrong XSPI2 register address");
+    }
+
+    unsafe {
+        let start = &raw mut __sitcm_text;
+        let end = &raw mut __eitcm_text;
+        let words = end.offset_from(start) as usize;
+        ptr::copy_nonoverlapping(&raw const __sitcm_text_lma, start, words);
+    }
+    asm::dsb();
+    asm::isb();
+}
+
+/// Erase the 4K sector at flash offset `addr`.
+pub fn erase_sector(addr: u32) {
+    // SAFETY: init() has copied the routine to ITCM.
+    unsafe { itcm_erase_sector(addr) };
+}
+
+/// Erase the sector-aligned range `start..end`.
+pub fn erase_range(start: u32, end: u32) {
+    for addr in (start..end).step_by(SECTOR_SIZE as usize) {
+        erase_sector(addr);
+    }
+}
+
+/// Read `buf.len()` bytes at flash offset `addr`, through the memory-mapped
+/// window.
+pub fn read(addr: u32, buf: &mut [u8]) {
+    let src = (XSPI2_MAPPED_BASE + addr as usize) as *const u8;
+    // SAFETY: the flash is memory-mapped, and the MPU allows reading it.
+    unsafe { ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+}
+
+/// Program `data` at flash offset `addr`. The area must have been erased.
+pub fn program(addr: u32, data: &[u8]) {
+    let mut place = addr;
+    let mut chunk_start = 0;
+
+    while chunk_start < data.len() {
+        let max_chunk_size = PAGE_SIZE - (place as usize % PAGE_SIZE);
+        let chunk_size = max_chunk_size.min(data.len() - chunk_start);
+
+        // DTR transfers must have an even length: pad with 0xFF, which leaves
+        // the flash contents unchanged. Done here, as copying may call
+        // memcpy.
+        let mut page = [0xFFu8; PAGE_SIZE];
+        let offset = (place & 1) as usize;
+        page[offset..offset + chunk_size]
+            .copy_from_slice(&data[chunk_start..chunk_start + chunk_size]);
+        let padded_len = (offset + chunk_size + 1) & !1;
+
+        // SAFETY: init() has copied the routine to ITCM, and the padded
+        // chunk does not cross a page boundary.
+        unsafe { itcm_program_page(place & !1, page.as_ptr() as u32, padded_len as u32) };
+        place += chunk_size as u32;
+        chunk_start += chunk_size;
+    }
+}
+
+/// The flash, for code executing in place: read through the memory-mapped
+/// window, and programmed and erased with the routines above. Implements the
+/// `embedded-storage` traits (see `nor_flash`), e.g. for `FirmwareUpdater`.
+/// `init()` must have been called.
+pub struct XipFlash;
+
+#[unsafe(link_section = ".itcm_text")]
+#[inline(never)]
+unsafe fn itcm_erase_sector(addr: u32) {
+    unsafe {
+        let session = Session::begin();
+        write_enable();
+        command(CMD_SECTOR_ERASE, Some(addr), 0, 0);
+        wait_write_finish();
+        session.end(addr, SECTOR_SIZE);
+    }
+}
+
+/// Program the `len` bytes at RAM address `data` at flash offset `addr`.
+/// `addr` and `len` are even, and the range is within one page.
+#[unsafe(link_section = ".itcm_text")]
+#[inline(never)]
+unsafe fn itcm_program_page(addr: u32, data: u32, len: u32) {
+    unsafe {
+        let session = Session::begin();
+        write_enable();
+        command(CMD_PAGE_PROGRAM, Some(addr), data, len);
+        wait_write_finish();
+        session.end(addr, len);
+    }
+}
+
+/// State saved while the flash is taken out of memory-mapped mode.
+struct Session {
+    primask: u32,
+    cr: u32,
+    ccr: u32,
+    tcr: u32,
+    ir: u32,
+    mpu_rnr: u32,
+    flash_rasr: u32,
+}
+
+impl Session {
+    /// Mask interrupts, make the flash region inaccessible, and leave
+    /// memory-mapped mode if it is on.
+    #[unsafe(link_section = ".itcm_text")]
+    #[inline(always)]
+    unsafe fn begin() -> Self {
+        unsafe {
+            let primask;
+            core::arch::asm!(
+                "mrs {}, PRIMASK",
+                "cpsid i",
+                out(reg) primask,
+                options(nostack, preserves_flags)
+            );
+
+            dsb();
+            let mpu_rnr = read32(MPU_RNR);
+            write32(MPU_RNR, REGION_FLASH);
+            let flash_rasr = read32(MPU_RASR);
+            write32(MPU_RASR, 0);
+            dsb();
+            isb();
+
+            let session = Self {
+                primask,
+                cr: read32(XSPI_CR),
+                ccr: read32(XSPI_CCR),
+                tcr: read32(XSPI_TCR),
+                ir: read32(XSPI_IR),
+                mpu_rnr,
+                flash_rasr,
+            };
+            write32(XSPI_CR, session.cr | CR_ABORT);
+            while read32(XSPI_CR) & CR_ABORT != 0 {}
+            while read32(XSPI_SR) & SR_BUSY != 0 {}
+            session
+        }
+    }
+
+    /// Restore the XSPI and the flash region as they were found, invalidate
+    /// the caches for the modified range and the branch predictor, and
+    /// restore the interrupt mask.
+    #[unsafe(link_section = ".itcm_text")]
+    #[inline(always)]
+    unsafe fn end(self, addr: u32, len: u32) {
+        unsafe {
+            write32(XSPI_CCR, self.ccr);
+            write32(XSPI_TCR, self.tcr);
+            write32(XSPI_IR, self.ir);
+            write32(XSPI_CR, self.cr);
+
+            dsb();
+            write32(MPU_RNR, REGION_FLASH);
+            write32(MPU_RASR, self.flash_rasr);
+            write32(MPU_RNR, self.mpu_rnr);
+
+            dsb();
+            let start = (XSPI2_MAPPED_BASE as u32).wrapping_add(addr);
+            let end = start.wrapping_add(len);
+            let mut line = start & !(CACHE_LINE_SIZE - 1);
+            while line < end {
+                write32(DCIMVAC, line);
+                line = line.wrapping_add(CACHE_LINE_SIZE);
+            }
+            write32(ICIALLU, 0);
+            write32(BPIALL, 0);
+            dsb();
+            isb();
+
+            if self.primask & PRIMASK_PM == 0 {
+                core::arch::asm!("cpsie i", options(nostack, preserves_flags));
+            }
+        }
+    }
+}
+
+/// Run one indirect-mode transfer, writing the `len` bytes at RAM address
+/// `data` (if any).
+#[unsafe(link_section = ".itcm_text")]
+#[inline(always)]
+unsafe fn command(instruction: u32, address: Option<u32>, data: u32, len: u32) {
+    unsafe {
+        write32(XSPI_CR, (read32(XSPI_CR) & !CR_FMODE_MASK) | CR_FMODE_WRITE);
+        write32(XSPI_CCR, ccr(address.is_some(), len != 0));
+        write32(XSPI_TCR, read32(XSPI_TCR) & !TCR_DCYC_MASK);
+        if len != 0 {
+            write32(XSPI_DLR, len.wrapping_sub(1));
+        }
+        write32(XSPI_IR, instruction);
+        if let Some(address) = address {
+            write32(XSPI_AR, address);
+        }
+
+        let mut i = 0;
+        while i < len {
+            write8(XSPI_DR, read8(data.wrapping_add(i)));
+            i = i.wrapping_add(1);
+        }
+        wait_transfer_complete();
+    }
+}
+
+#[unsafe(link_section = ".itcm_text")]
+#[inline(always)]
+unsafe fn write_enable() {
+    unsafe { command(CMD_WRITE_ENABLE, None, 0, 0) };
+}
+
+#[unsafe(link_section = ".itcm_text")]
+#[inline(always)]
+unsafe fn read_status() -> u32 {
+    unsafe {
+        write32(XSPI_CR, (read32(XSPI_CR) & !CR_FMODE_MASK) | CR_FMODE_READ);
+        write32(XSPI_CCR, ccr(true, true));
+        write32(
+            XSPI_TCR,
+            (read32(XSPI_TCR) & !TCR_DCYC_MASK) | DUMMY_CYCLES_REG,
+        );
+        write32(XSPI_DLR, 1); // Two bytes, as required for DTR.
+        write32(XSPI_IR, CMD_READ_STATUS);
+        write32(XSPI_AR, 0);
+        wait_transfer_complete();
+
+        let status = read8(XSPI_DR);
+        let _ = read8(XSPI_DR);
+        status
+    }
+}
+
+#[unsafe(link_section = ".itcm_text")]
+#[inline(always)]
+unsafe fn wait_write_finish() {
+    unsafe { while read_status() & STATUS_WIP != 0 {} }
+}
+
+#[unsafe(link_section = ".itcm_text")]
+#[inline(always)]
+unsafe fn wait_transfer_complete() {
+    unsafe {
+        while read32(XSPI_SR) & SR_TCF == 0 {}
+        write32(XSPI_FCR, FCR_CTCF);
+        while read32(XSPI_SR) & SR_BUSY != 0 {}
+    }
+}
+
+// Register accesses and barriers, in assembly so they cannot become calls.
+
+#[unsafe(link_section = ".itcm_text")]
+#[inline(always)]
+unsafe fn read32(addr: u32) -> u32 {
+    let value;
+    unsafe {
+        core::arch::asm!(
+            "ldr {}, [{}]",
+            out(reg) value,
+            in(reg) addr,
+            options(nostack, preserves_flags)
+        )
+    };
+    value
+}
+
+#[unsafe(link_section = ".itcm_text")]
+#[inline(always)]
+unsafe fn write32(addr: u32, value: u32) {
+    unsafe {
+        core::arch::asm!(
+            "str {}, [{}]",
+            in(reg) value,
+            in(reg) addr,
+            options(nostack, preserves_flags)
+        )
+    };
+}
+
+#[unsafe(link_section = ".itcm_text")]
+#[inline(always)]
+unsafe fn read8(addr: u32) -> u32 {
+    let value;
+    unsafe {
+        core::arch::asm!(
+            "ldrb {}, [{}]",
+            out(reg) value,
+            in(reg) addr,
+            options(nostack, preserves_flags)
+        )
+    };
+    value
+}
+
+#[unsafe(link_section = ".itcm_text")]
+#[inline(always)]
+unsafe fn write8(addr: u32, value: u32) {
+    unsafe {
+        core::arch::asm!(
+            "strb {}, [{}]",
+            in(reg) value,
+            in(reg) addr,
+            options(nostack, preserves_flags)
+        )
+    };
+}
+
+#[unsafe(link_section = ".itcm_text")]
+#[inline(always)]
+unsafe fn dsb() {
+    unsafe { core::arch::asm!("dsb sy", options(nostack, preserves_flags)) };
+}
+
+#[unsafe(link_section = ".itcm_text")]
+#[inline(always)]
+unsafe fn isb() {
+    unsafe { core::arch::asm!("isb sy", options(nostack, preserves_flags)) };
+}
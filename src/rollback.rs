@@ -0,0 +1,90 @@
+// Anti-rollback security counter.
+//
+// Images carry a security version in their header (see `image`), raised by
+// releases that fix vulnerabilities. The bootloader refuses to swap in or
+// start an image whose security version is below the security counter, even
+// a validly signed one. Once the application confirms an update, the counter
+// is raised to the update's security version, so older images can no longer
+// be installed. An update that is not confirmed leaves the counter alone, so
+// the image it replaced can still be reverted to.
+//
+// The counter is kept in the second half of the octal flash's secured OTP
+// area, which can be programmed but never erased: it is the number of
+// programmed 2-byte markers from the start of the region. Raising it programs
+// the markers up to the new value. A power loss in between leaves the counter
+// somewhere between the old and the new value, and raising it again at the
+// next boot completes it.
+
+use crate::image::ImageHeader;
+use crate::mx25uw25645g::{OpiFlashMemory, SECURED_OTP_SIZE};
+use crate::swap::MARKER_SIZE;
+
+const MARKER_SET: [u8; MARKER_SIZE] = [0; MARKER_SIZE];
+
+/// Start of the counter in the secured OTP area. The first half is left to
+/// the application, e.g. for identifiers.
+const COUNTER_OFFSET: usize = SECURED_OTP_SIZE / 2;
+
+/// The highest security version the counter can hold.
+pub const MAX_SECURITY_VERSION: u32 = ((SECURED_OTP_SIZE - COUNTER_OFFSET) / MARKER_SIZE) as u32;
+
+const COUNTER_SIZE: usize = MAX_SECURITY_VERSION as usize * MARKER_SIZE;
+
+/// Why an image is refused by the anti-rollback check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RollbackError {
+    /// The image's security version is below the security counter.
+    Downgrade { security_version: u32, counter: u32 },
+    /// The security version is above `MAX_SECURITY_VERSION`.
+    OutOfRange(u32),
+}
+
+/// Check that the image described by `header` may be installed or started,
+/// with the security counter at `counter`.
+pub fn check(header: &ImageHeader, counter: u32) -> Result<(), RollbackError> {
+    let security_version = header.security_version;
+    if security_version > MAX_SECURITY_VERSION {
+        return Err(RollbackError::OutOfRange(security_version));
+    }
+    if security_version < counter {
+        return Err(RollbackError::Downgrade {
+            security_version,
+            counter,
+        });
+    }
+    Ok(())
+}
+
+/// The current security counter.
+pub fn read_counter(flash: &mut OpiFlashMemory) -> u32 {
+    let mut markers = [0; COUNTER_SIZE];
+    flash.read_otp(COUNTER_OFFSET as u32, &mut markers);
+    markers
+        .chunks(MARKER_SIZE)
+        .take_while(|marker| *marker == MARKER_SET)
+        .count() as u32
+}
+
+/// Raise the security counter to `security_version`, if below it. Returns
+/// the counter afterwards, which is below `security_version` only if
+/// programming failed.
+pub fn raise_counter(
+    flash: &mut OpiFlashMemory,
+    security_version: u32,
+) -> Result<u32, RollbackError> {
+    if security_version > MAX_SECURITY_VERSION {
+        return Err(RollbackError::OutOfRange(security_version));
+    }
+    let counter = read_counter(flash);
+    if counter >= security_version {
+        return Ok(counter);
+    }
+
+    // Also reprograms a marker torn by a power loss, which is harmless: its
+    // programmed bits stay programmed.
+    let markers = [0; COUNTER_SIZE];
+    let from = counter as usize * MARKER_SIZE;
+    let to = security_version as usize * MARKER_SIZE;
+    flash.program_otp((COUNTER_OFFSET + from) as u32, &markers[from..to]);
+    Ok(read_counter(flash))
+}
@@ -0,0 +1,169 @@
+// Ed25519 signatures of firmware images.
+//
+// A signed image has a trailer holding the 64-byte Ed25519 signature of the
+// SHA-256 digest of the image up to the end of the payload: header slot and
+// payload. The header is covered, so its trailer size and flags cannot be
+// changed without breaking the signature.
+//
+// The digest is computed directly over the memory-mapped flash, so the image
+// is never copied to RAM. With `HardwareSha256`, the flash is read by DMA.
+//
+// The public key is compiled into the bootloader: set the BOOT_PUBLIC_KEY
+// environment variable to a file holding the raw 32-byte key when building
+// (see build.rs). It always takes priority, as blank OTP can be programmed by
+// anyone with debug access, who could then install their own key. Only with
+// the `otp-public-key` feature, and if no key is compiled in, is the key read
+// from the internal OTP area instead. The OTP block holding it must then be
+// locked when provisioning the device, before it leaves trusted hands.
+//
+// Images are signed on the host by `cargo package-image` (see
+// examples/package_image.rs). `public_key()` is only built for the target.
+
+#[cfg(target_os = "none")]
+use core::ptr;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+
+use crate::digest::{Digest, SHA256_LEN};
+use crate::image::ImageHeader;
+
+pub const SIGNATURE_LEN: usize = 64;
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// Public key location in the internal OTP area (FLASH_OTP in memory.x).
+#[cfg(target_os = "none")]
+const OTP_PUBLIC_KEY: *const [u8; PUBLIC_KEY_LEN] = 0x08FF_0000 as *const _;
+
+/// Compiled-in public key, all 0xFF if none was given.
+#[cfg(target_os = "none")]
+const COMPILED_PUBLIC_KEY: &[u8; PUBLIC_KEY_LEN] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/boot_public_key.bin"));
+
+/// Why a signature was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum SignatureError {
+    /// The image has no trailer.
+    Unsigned,
+    /// The trailer is not a signature.
+    BadTrailer,
+    /// No public key compiled in, nor in OTP with `otp-public-key`.
+    NoKey,
+    /// The public key is not a valid Ed25519 key.
+    BadKey,
+    /// The signature does not match the image.
+    Invalid,
+}
+
+/// The key to check images against: the compiled-in one, otherwise, with
+/// the `otp-public-key` feature, the one programmed in OTP.
+#[cfg(target_os = "none")]
+pub fn public_key() -> Result<VerifyingKey, SignatureError> {
+    let otp = if cfg!(feature = "otp-public-key") {
+        // SAFETY: the OTP area is always readable.
+        unsafe { ptr::read_volatile(OTP_PUBLIC_KEY) }
+    } else {
+        [0xFF; PUBLIC_KEY_LEN]
+    };
+    let key = [COMPILED_PUBLIC_KEY, &otp]
+        .into_iter()
+        .find(|key| key.iter().any(|&b| b != 0xFF))
+        .ok_or(SignatureError::NoKey)?;
+    VerifyingKey::from_bytes(key).map_err(|_| SignatureError::BadKey)
+}
+
+/// The signed digest of `image`, described by `header`.
+pub fn image_digest(
+    image: &[u8],
+    header: &ImageHeader,
+    digest: &mut impl Digest,
+) -> [u8; SHA256_LEN] {
+    digest.reset();
+    digest.update(&image[..header.payload_range().end]);
+    digest.finish()
+}
+
+/// Check the signature in the trailer of `image`, described by a header
+/// that was already validated against it.
+pub fn verify_image(
+    image: &[u8],
+    header: &ImageHeader,
+    key: &VerifyingKey,
+    digest: &mut impl Digest,
+) -> Result<(), SignatureError> {
+    if header.trailer_size == 0 {
+        return Err(SignatureError::Unsigned);
+    }
+    let signature: &[u8; SIGNATURE_LEN] = image[header.trailer_range()]
+        .try_into()
+        .map_err(|_| SignatureError::BadTrailer)?;
+
+    let digest = image_digest(image, header, digest);
+    key.verify_strict(&digest, &Signature::from_bytes(signature))
+        .map_err(|_| SignatureError::Invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+    use crate::checksum::{CRC32_ISO_HDLC, SoftwareCrc};
+    use crate::digest::SoftwareSha256;
+    use crate::image::{FLAG_TRAILER, HEADER_SLOT_SIZE};
+
+    const PAYLOAD_LEN: usize = 512;
+    const IMAGE_LEN: usize = HEADER_SLOT_SIZE + PAYLOAD_LEN + SIGNATURE_LEN;
+
+    /// An image signed with `key`, as by `cargo package-image`.
+    fn signed_image(key: &SigningKey) -> ([u8; IMAGE_LEN], ImageHeader) {
+        let mut image = [0; IMAGE_LEN];
+        let header = ImageHeader {
+            image_version: 1,
+            load_address: 0x7002_0400,
+            image_size: PAYLOAD_LEN as u32,
+            entry_point: 0x7002_0501,
+            flags: FLAG_TRAILER,
+            trailer_size: SIGNATURE_LEN as u32,
+            payload_crc: 0,
+            security_version: 0,
+        };
+        header.write(
+            &mut image[..HEADER_SLOT_SIZE],
+            &mut SoftwareCrc::new(CRC32_ISO_HDLC),
+        );
+        let digest = image_digest(&image, &header, &mut SoftwareSha256::new());
+        image[header.trailer_range()].copy_from_slice(&key.sign(&digest).to_bytes());
+        (image, header)
+    }
+
+    #[test]
+    fn signed_image_verifies() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let (image, header) = signed_image(&key);
+        let result = verify_image(
+            &image,
+            &header,
+            &key.verifying_key(),
+            &mut SoftwareSha256::new(),
+        );
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn tampered_image_or_other_key_is_refused() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        let (mut image, header) = signed_image(&key);
+        let mut sha = SoftwareSha256::new();
+        assert_eq!(
+            verify_image(&image, &header, &other_key, &mut sha),
+            Err(SignatureError::Invalid)
+        );
+        image[HEADER_SLOT_SIZE + 100] ^= 1;
+        assert_eq!(
+            verify_image(&image, &header, &key.verifying_key(), &mut sha),
+            Err(SignatureError::Invalid)
+        );
+    }
+}
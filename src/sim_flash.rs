@@ -0,0 +1,222 @@
+// Simulated NOR flash, for power-loss simulation (see `power_loss`).
+//
+// Behaves like the octal flash as seen through `nor_flash`: 2-byte writes,
+// 4K sectors, programmed a 256-byte page at a time. Like real NOR flash,
+// programming can only clear bits: writing a 1 over a 0 is refused, so data
+// must be erased before it is rewritten. Programming bits that are already
+// 0 again is allowed, as the swap does to complete interrupted markers.
+//
+// Every page program and sector erase is an operation, after which the
+// power can be cut. The interrupted operation then either has no effect, or
+// is torn: it only affects the first half of its page or sector. Without
+// power, all further operations fail, until `restore_power()`.
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+pub const SIM_PAGE_SIZE: usize = 256;
+pub const SIM_SECTOR_SIZE: usize = 4096;
+const SIM_WRITE_SIZE: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum SimError {
+    NotAligned,
+    OutOfBounds,
+    /// A write would have to set a programmed bit back to 1.
+    NotErased {
+        offset: u32,
+    },
+    PowerCut,
+}
+
+impl NorFlashError for SimError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::NotAligned => NorFlashErrorKind::NotAligned,
+            Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Self::NotErased { .. } | Self::PowerCut => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// When to cut the power.
+#[derive(Debug, Clone, Copy)]
+struct Cut {
+    /// Operation count at which the power is lost.
+    at: usize,
+    torn: bool,
+}
+
+/// What happens to an operation.
+enum Outcome {
+    Complete,
+    Torn,
+    Lost,
+}
+
+/// A flash of `N` bytes, erased when created.
+#[derive(Clone)]
+pub struct SimFlash<const N: usize> {
+    data: [u8; N],
+    operations: usize,
+    cut: Option<Cut>,
+    powered: bool,
+}
+
+impl<const N: usize> SimFlash<N> {
+    pub fn new() -> Self {
+        assert!(N.is_multiple_of(SIM_SECTOR_SIZE));
+        Self {
+            data: [0xFF; N],
+            operations: 0,
+            cut: None,
+            powered: true,
+        }
+    }
+
+    pub fn contents(&self) -> &[u8; N] {
+        &self.data
+    }
+
+    /// Program and erase operations completed so far.
+    pub fn operations(&self) -> usize {
+        self.operations
+    }
+
+    /// Cut the power after `operations` more operations complete. If `torn`,
+    /// the next operation takes partial effect.
+    pub fn cut_power_after(&mut self, operations: usize, torn: bool) {
+        self.cut = Some(Cut {
+            at: self.operations + operations,
+            torn,
+        });
+    }
+
+    pub fn restore_power(&mut self) {
+        self.cut = None;
+        self.powered = true;
+    }
+
+    fn outcome(&mut self) -> Outcome {
+        if !self.powered {
+            return Outcome::Lost;
+        }
+        match self.cut {
+            Some(cut) if cut.at == self.operations => {
+                self.powered = false;
+                if cut.torn {
+                    Outcome::Torn
+                } else {
+                    Outcome::Lost
+                }
+            }
+            _ => {
+                self.operations += 1;
+                Outcome::Complete
+            }
+        }
+    }
+
+    fn check(&self, from: u32, to: u32, align: usize) -> Result<(), SimError> {
+        if from > to || to as usize > N {
+            return Err(SimError::OutOfBounds);
+        }
+        if !(from as usize).is_multiple_of(align) || !(to as usize).is_multiple_of(align) {
+            return Err(SimError::NotAligned);
+        }
+        Ok(())
+    }
+
+    /// Program one page, or part of it.
+    fn program_page(&mut self, offset: usize, bytes: &[u8]) -> Result<(), SimError> {
+        let page = &self.data[offset..offset + bytes.len()];
+        if let Some(i) = page
+            .iter()
+            .zip(bytes)
+            .position(|(&old, &new)| new & !old != 0)
+        {
+            return Err(SimError::NotErased {
+                offset: (offset + i) as u32,
+            });
+        }
+        let len = match self.outcome() {
+            Outcome::Complete => bytes.len(),
+            Outcome::Torn => bytes.len() / 2,
+            Outcome::Lost => 0,
+        };
+        let page = &mut self.data[offset..offset + bytes.len()];
+        for (old, new) in page.iter_mut().zip(&bytes[..len]) {
+            *old &= new;
+        }
+        if len < bytes.len() {
+            return Err(SimError::PowerCut);
+        }
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, offset: usize) -> Result<(), SimError> {
+        let len = match self.outcome() {
+            Outcome::Complete => SIM_SECTOR_SIZE,
+            Outcome::Torn => SIM_SECTOR_SIZE / 2,
+            Outcome::Lost => 0,
+        };
+        self.data[offset..offset + len].fill(0xFF);
+        if len < SIM_SECTOR_SIZE {
+            return Err(SimError::PowerCut);
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for SimFlash<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ErrorType for SimFlash<N> {
+    type Error = SimError;
+}
+
+impl<const N: usize> ReadNorFlash for SimFlash<N> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), SimError> {
+        self.check(offset, offset + bytes.len() as u32, Self::READ_SIZE)?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<const N: usize> NorFlash for SimFlash<N> {
+    const WRITE_SIZE: usize = SIM_WRITE_SIZE;
+    const ERASE_SIZE: usize = SIM_SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), SimError> {
+        self.check(from, to, Self::ERASE_SIZE)?;
+        for sector in (from as usize..to as usize).step_by(SIM_SECTOR_SIZE) {
+            self.erase_sector(sector)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), SimError> {
+        self.check(offset, offset + bytes.len() as u32, Self::WRITE_SIZE)?;
+        let mut offset = offset as usize;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let len = bytes.len().min(SIM_PAGE_SIZE - offset % SIM_PAGE_SIZE);
+            self.program_page(offset, &bytes[..len])?;
+            offset += len;
+            bytes = &bytes[len..];
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,401 @@
+// Power-fail-safe swap of the ACTIVE and DFU partitions, with trial boots
+// and rollback.
+//
+// DFU is at least one sector larger than ACTIVE. The swap works sector by
+// sector, from the last one: ACTIVE sector i is moved to DFU sector i + 1,
+// then DFU sector i to ACTIVE sector i. Afterwards, ACTIVE holds the update,
+// and DFU the previous image, shifted by one sector. A revert does the
+// opposite, from the first sector: ACTIVE sector i is moved to DFU sector i,
+// then DFU sector i + 1 to ACTIVE sector i.
+//
+// Each move (a "step") erases its destination and copies its source there.
+// The source of a step is only overwritten by the next step, so a step that
+// was interrupted can simply be done again. Once a step is complete, its
+// marker is programmed in the journal in BOOTLOADER_STATE, and on the next
+// boot, the swap or revert resumes with the first step without a marker.
+//
+// After a swap, the update is booted on trial. Each trial boot is counted
+// with a marker, and the application confirms the update with
+// `mark_booted()`. If it has not after `MAX_TRIAL_BOOTS` boots, or if the
+// watchdog reset the device during a trial boot, the bootloader reverts to
+// the previous image. It also reverts an update it refuses to start.
+//
+// BOOTLOADER_STATE is only erased when a new swap is requested, never while
+// one is in progress. After that, markers are only programmed, from erased
+// (all ones) to set (all zeros), each one once:
+//
+//   offset  marker
+//        0  swap requested
+//        2  swap done, trial started
+//        4  update confirmed by the application
+//        6  revert decided
+//        8  revert done
+//     4096  swap journal: one marker per step, 2 * (ACTIVE sectors) of them
+//     8192  trial boots: one marker per boot, MAX_TRIAL_BOOTS of them
+//    12288  revert journal: one marker per step
+//
+// A marker that was being programmed when power was lost may read as neither
+// erased nor set. It counts as not set, and is programmed again.
+//
+// The application's side of an update is `FirmwareUpdater`, which takes the
+// place of embassy-boot's type of the same name: it erases DFU, writes the
+// update there, requests the swap, and confirms the update once booted.
+//
+// This replaces embassy-boot, whose swap uses the same one-sector shift and
+// also journals its progress with write-once markers. It cannot be extended
+// with what the bootloader needs, as its state layout is internal:
+//
+// - Its `BootLoader` starts a requested swap unconditionally. Here the
+//   bootloader checks the update (header, signature, security version)
+//   first, and cancels the request if it is refused, so a compromised
+//   application cannot bypass the check it would otherwise do itself.
+// - Its trial lasts a single boot, and does not notice watchdog resets.
+// - The A/B mode (see `ab`) keeps per-slot markers in the same partition,
+//   and the power-loss check (see `power_loss`) runs this code on the host.
+
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+
+/// Size of a marker: the write size of the octal flash.
+pub const MARKER_SIZE: usize = 2;
+
+/// Boots of an update without confirmation before it is reverted.
+pub const MAX_TRIAL_BOOTS: usize = 3;
+
+const MARKER_SET: [u8; MARKER_SIZE] = [0; MARKER_SIZE];
+
+// Marker offsets in BOOTLOADER_STATE.
+const REQUESTED: u32 = 0;
+const SWAPPED: u32 = 2;
+const CONFIRMED: u32 = 4;
+const REVERT: u32 = 6;
+const REVERTED: u32 = 8;
+const SWAP_JOURNAL: u32 = 4096;
+const TRIAL_BOOTS: u32 = 8192;
+const REVERT_JOURNAL: u32 = 12288;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum State {
+    /// No update: ACTIVE is booted as it is.
+    Boot,
+    /// An update waits in DFU, and the first `steps_done` steps of its swap
+    /// are complete.
+    Swap { steps_done: usize },
+    /// The update was swapped into ACTIVE, and booted `boots` times without
+    /// confirmation.
+    Trial { boots: usize },
+    /// The update in ACTIVE was confirmed.
+    Confirmed,
+    /// The update was not confirmed, and the first `steps_done` steps of the
+    /// revert are complete.
+    Revert { steps_done: usize },
+    /// The previous image is back in ACTIVE.
+    Reverted,
+}
+
+/// A sequence of steps, with its journal.
+#[derive(Clone, Copy)]
+enum Journal {
+    Swap,
+    Revert,
+}
+
+impl Journal {
+    fn marker(self, step: usize) -> u32 {
+        let start = match self {
+            Self::Swap => SWAP_JOURNAL,
+            Self::Revert => REVERT_JOURNAL,
+        };
+        start + (step * MARKER_SIZE) as u32
+    }
+
+    /// The marker set once all steps are complete.
+    fn done(self) -> u32 {
+        match self {
+            Self::Swap => SWAPPED,
+            Self::Revert => REVERTED,
+        }
+    }
+}
+
+/// The partitions taking part in a swap: ACTIVE, DFU and BOOTLOADER_STATE.
+pub struct Partitions<F> {
+    pub active: F,
+    pub dfu: F,
+    pub state: F,
+}
+
+impl<F: NorFlash> Partitions<F> {
+    /// Panics if the partitions are too small for a swap.
+    pub fn new(active: F, dfu: F, state: F) -> Self {
+        let partitions = Self { active, dfu, state };
+        assert!(MARKER_SIZE.is_multiple_of(F::WRITE_SIZE));
+        assert!(partitions.active.capacity().is_multiple_of(F::ERASE_SIZE));
+        assert!(partitions.dfu.capacity() >= partitions.active.capacity() + F::ERASE_SIZE);
+        assert!(partitions.state.capacity() >= Journal::Revert.marker(partitions.steps()) as usize);
+        partitions
+    }
+
+    fn sectors(&self) -> usize {
+        self.active.capacity() / F::ERASE_SIZE
+    }
+
+    /// Steps of a complete swap or revert.
+    pub fn steps(&self) -> usize {
+        2 * self.sectors()
+    }
+
+    fn is_set(&mut self, offset: u32) -> Result<bool, F::Error> {
+        let mut marker = [0; MARKER_SIZE];
+        self.state.read(offset, &mut marker)?;
+        Ok(marker == MARKER_SET)
+    }
+
+    fn set(&mut self, offset: u32) -> Result<(), F::Error> {
+        self.state.write(offset, &MARKER_SET)
+    }
+
+    /// Number of set markers in a row, from `start`, up to `max`.
+    fn count(&mut self, start: u32, max: usize) -> Result<usize, F::Error> {
+        let mut count = 0;
+        while count < max && self.is_set(start + (count * MARKER_SIZE) as u32)? {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn state(&mut self) -> Result<State, F::Error> {
+        let steps = self.steps();
+        if !self.is_set(REQUESTED)? {
+            Ok(State::Boot)
+        } else if !self.is_set(SWAPPED)? {
+            let steps_done = self.count(Journal::Swap.marker(0), steps)?;
+            Ok(State::Swap { steps_done })
+        } else if self.is_set(CONFIRMED)? {
+            Ok(State::Confirmed)
+        } else if self.is_set(REVERTED)? {
+            Ok(State::Reverted)
+        } else if self.is_set(REVERT)? {
+            let steps_done = self.count(Journal::Revert.marker(0), steps)?;
+            Ok(State::Revert { steps_done })
+        } else {
+            let boots = self.count(TRIAL_BOOTS, MAX_TRIAL_BOOTS)?;
+            Ok(State::Trial { boots })
+        }
+    }
+
+    /// Drop a requested swap that was not started yet.
+    pub fn cancel(&mut self) -> Result<(), F::Error> {
+        assert_eq!(self.state()?, State::Swap { steps_done: 0 });
+        let capacity = self.state.capacity() as u32;
+        self.state.erase(0, capacity)
+    }
+
+    /// Reject the update booted on trial, e.g. because the bootloader found
+    /// ACTIVE invalid: the next `prepare_boot()` reverts it.
+    pub fn revert(&mut self) -> Result<(), F::Error> {
+        assert!(matches!(self.state()?, State::Trial { .. }));
+        self.set(REVERT)
+    }
+
+    /// Get ACTIVE ready to boot: complete a requested swap, or revert an
+    /// update that was not confirmed in time, resuming where they were
+    /// interrupted. `watchdog_reset` tells if the watchdog caused the last
+    /// reset. `buf` is used to copy sectors, and must divide a sector in
+    /// chunks of the write size.
+    ///
+    /// Returns the state ACTIVE is booted in. For `Trial`, this boot is
+    /// counted, and the watchdog should be started.
+    pub fn prepare_boot(
+        &mut self,
+        buf: &mut [u8],
+        watchdog_reset: bool,
+    ) -> Result<State, F::Error> {
+        assert!(F::ERASE_SIZE.is_multiple_of(buf.len()) && buf.len().is_multiple_of(F::WRITE_SIZE));
+        loop {
+            match self.state()? {
+                State::Swap { steps_done } => self.run(Journal::Swap, steps_done, buf)?,
+                // A watchdog reset before the first trial boot is not due to
+                // the update.
+                State::Trial { boots }
+                    if boots == MAX_TRIAL_BOOTS || (watchdog_reset && boots > 0) =>
+                {
+                    self.set(REVERT)?
+                }
+                State::Trial { boots } => {
+                    self.set(TRIAL_BOOTS + (boots * MARKER_SIZE) as u32)?;
+                    return Ok(State::Trial { boots: boots + 1 });
+                }
+                State::Revert { steps_done } => self.run(Journal::Revert, steps_done, buf)?,
+                state => return Ok(state),
+            }
+        }
+    }
+
+    /// Do the steps of `journal` from `steps_done` on.
+    fn run(&mut self, journal: Journal, steps_done: usize, buf: &mut [u8]) -> Result<(), F::Error> {
+        for step in steps_done..self.steps() {
+            self.step(journal, step, buf)?;
+            self.set(journal.marker(step))?;
+        }
+        self.set(journal.done())
+    }
+
+    /// Do step `step` of `journal`.
+    fn step(&mut self, journal: Journal, step: usize, buf: &mut [u8]) -> Result<(), F::Error> {
+        // Sectors of ACTIVE and DFU, and whether to move from ACTIVE to DFU.
+        let (active_sector, dfu_sector, to_dfu) = match journal {
+            Journal::Swap => {
+                let i = self.sectors() - 1 - step / 2;
+                if step.is_multiple_of(2) {
+                    (i, i + 1, true)
+                } else {
+                    (i, i, false)
+                }
+            }
+            Journal::Revert => {
+                let i = step / 2;
+                if step.is_multiple_of(2) {
+                    (i, i, true)
+                } else {
+                    (i, i + 1, false)
+                }
+            }
+        };
+        let active_offset = (active_sector * F::ERASE_SIZE) as u32;
+        let dfu_offset = (dfu_sector * F::ERASE_SIZE) as u32;
+        if to_dfu {
+            copy_sector(
+                &mut self.active,
+                active_offset,
+                &mut self.dfu,
+                dfu_offset,
+                buf,
+            )
+        } else {
+            copy_sector(
+                &mut self.dfu,
+                dfu_offset,
+                &mut self.active,
+                active_offset,
+                buf,
+            )
+        }
+    }
+}
+
+/// Erase the sector at `to_offset` in `to`, and copy the sector at
+/// `from_offset` in `from` there.
+fn copy_sector<F: NorFlash>(
+    from: &mut F,
+    from_offset: u32,
+    to: &mut F,
+    to_offset: u32,
+    buf: &mut [u8],
+) -> Result<(), F::Error> {
+    to.erase(to_offset, to_offset + F::ERASE_SIZE as u32)?;
+    for chunk in (0..F::ERASE_SIZE).step_by(buf.len()) {
+        let chunk = chunk as u32;
+        from.read(from_offset + chunk, buf)?;
+        // Already erased.
+        if buf.iter().all(|&b| b == 0xFF) {
+            continue;
+        }
+        to.write(to_offset + chunk, buf)?;
+    }
+    Ok(())
+}
+
+/// The application's side of updates, over the DFU and BOOTLOADER_STATE
+/// partitions.
+///
+/// With the flash service, these are `Partition`s of a `FlashClient`. An
+/// application executing in place can use `ram_flash::XipFlash` instead.
+pub struct FirmwareUpdater<F> {
+    dfu: F,
+    state: F,
+}
+
+impl<F: AsyncNorFlash> FirmwareUpdater<F> {
+    pub fn new(dfu: F, state: F) -> Self {
+        Self { dfu, state }
+    }
+
+    /// The state as seen by the application, see `state_async()`.
+    pub async fn get_state(&mut self) -> Result<State, F::Error> {
+        state_async(&mut self.state).await
+    }
+
+    /// Erase DFU, before writing an update with `write_firmware()`.
+    pub async fn prepare_update(&mut self) -> Result<(), F::Error> {
+        let capacity = self.dfu.capacity() as u32;
+        self.dfu.erase(0, capacity).await
+    }
+
+    /// Write `data` at `offset` in DFU, which must have been erased by
+    /// `prepare_update()`.
+    pub async fn write_firmware(&mut self, offset: usize, data: &[u8]) -> Result<(), F::Error> {
+        self.dfu.write(offset as u32, data).await
+    }
+
+    /// Request the swap of the update written to DFU, on next boot. The
+    /// bootloader checks the image before swapping it in.
+    pub async fn mark_updated(&mut self) -> Result<(), F::Error> {
+        request_swap(&mut self.state).await
+    }
+
+    /// Confirm the update running on trial, see `mark_booted()`.
+    pub async fn mark_booted(&mut self) -> Result<(), F::Error> {
+        mark_booted(&mut self.state).await
+    }
+}
+
+/// Ask the bootloader to swap in the update written to DFU, on next boot.
+/// Called by the application, with BOOTLOADER_STATE.
+pub async fn request_swap<F: AsyncNorFlash>(state: &mut F) -> Result<(), F::Error> {
+    let capacity = state.capacity() as u32;
+    state.erase(0, capacity).await?;
+    state.write(REQUESTED, &MARKER_SET).await
+}
+
+async fn is_set_async<F: AsyncNorFlash>(state: &mut F, offset: u32) -> Result<bool, F::Error> {
+    let mut marker = [0; MARKER_SIZE];
+    state.read(offset, &mut marker).await?;
+    Ok(marker == MARKER_SET)
+}
+
+/// The state as seen by the application, with BOOTLOADER_STATE. The
+/// application only runs once a swap or revert is done, so the steps of
+/// `Swap` and `Revert` are not counted.
+pub async fn state_async<F: AsyncNorFlash>(state: &mut F) -> Result<State, F::Error> {
+    if !is_set_async(state, REQUESTED).await? {
+        Ok(State::Boot)
+    } else if !is_set_async(state, SWAPPED).await? {
+        Ok(State::Swap { steps_done: 0 })
+    } else if is_set_async(state, CONFIRMED).await? {
+        Ok(State::Confirmed)
+    } else if is_set_async(state, REVERTED).await? {
+        Ok(State::Reverted)
+    } else if is_set_async(state, REVERT).await? {
+        Ok(State::Revert { steps_done: 0 })
+    } else {
+        let mut boots = 0;
+        while boots < MAX_TRIAL_BOOTS
+            && is_set_async(state, TRIAL_BOOTS + (boots * MARKER_SIZE) as u32).await?
+        {
+            boots += 1;
+        }
+        Ok(State::Trial { boots })
+    }
+}
+
+/// Confirm the update running on trial, so it is kept. Called by the
+/// application, with BOOTLOADER_STATE, once it is known to work. Does
+/// nothing outside of a trial.
+pub async fn mark_booted<F: AsyncNorFlash>(state: &mut F) -> Result<(), F::Error> {
+    if let State::Trial { .. } = state_async(state).await? {
+        state.write(CONFIRMED, &MARKER_SET).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,253 @@
+// Compile-time calculator for the XSPI kernel clock, generated by PLL2_S.
+//
+// The XSPI kernel clock is post-divided by the XSPI peripheral's own clock
+// prescaler to obtain the flash bus clock. The PLL always has a 50% duty-cycle
+// output on post-dividers divs and divt when VCOH is selected (PLLxVCOSEL = 0),
+// but we avoid that uncertainty by only using even XSPI division ratios
+// (clock_prescaler + 1 = 2, 4, 6, ...), which always give symmetric high and
+// low times on the bus clock.
+//
+// The flash starts in SPI mode after reset and is switched to OPI mode later
+// on, so two bus frequencies are needed from the same kernel clock: only the
+// XSPI clock prescaler is changed when switching modes.
+//
+// Everything here is plain integer arithmetic, so it also builds and is
+// tested on the host. `board` turns the result into HAL settings.
+
+/// PLL input (reference) frequency limits, after the pre-divider.
+const PLL_REF_MIN_HZ: u64 = 1_000_000;
+const PLL_REF_MAX_HZ: u64 = 16_000_000;
+
+/// PLL VCO frequency limits (VCOH).
+const PLL_VCO_MIN_HZ: u64 = 400_000_000;
+const PLL_VCO_MAX_HZ: u64 = 1_600_000_000;
+
+/// PLL divider ranges.
+const PLL_PREDIV_MAX: u64 = 63;
+const PLL_MUL_MIN: u64 = 8;
+const PLL_MUL_MAX: u64 = 420;
+const PLL_DIVST_MAX: u64 = 8;
+
+/// Maximum XSPI kernel clock we are willing to feed into the peripheral.
+const XSPI_KERNEL_MAX_HZ: u64 = 400_000_000;
+
+/// Maximum XSPI division ratio (clock_prescaler is 8 bits, ratio = value + 1).
+const XSPI_DIV_MAX: u64 = 256;
+
+/// A complete PLL2_S and XSPI prescaler configuration. Dividers and the
+/// multiplier are the actual ratios, not register values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XspiClocks {
+    /// PLL2 pre-divider (DIVM2).
+    pub prediv: u8,
+    /// PLL2 multiplier (DIVN2).
+    pub mul: u16,
+    /// PLL2 S post-divider, feeding the XSPI kernel clock.
+    pub divs: u8,
+    /// XSPI clock prescaler to use while the flash is in SPI mode (register
+    /// value: the division ratio minus one).
+    pub spi_prescaler: u8,
+    /// XSPI clock prescaler to use while the flash is in OPI mode (register
+    /// value: the division ratio minus one).
+    pub opi_prescaler: u8,
+    /// Resulting XSPI kernel clock, in Hz.
+    pub kernel_hz: u32,
+    /// Resulting flash bus clock in SPI mode, in Hz.
+    pub spi_bus_hz: u32,
+    /// Resulting flash bus clock in OPI mode, in Hz.
+    pub opi_bus_hz: u32,
+}
+
+/// Smallest even division ratio that brings `kernel` down to at most `target`.
+const fn even_div(kernel: u64, target: u64) -> u64 {
+    let div = kernel.div_ceil(target);
+    if div.is_multiple_of(2) { div } else { div + 1 }
+}
+
+/// Find PLL2 settings and XSPI prescalers for the requested bus clocks, from
+/// an HSE clock of `hse_hz`. All frequencies are in Hz.
+///
+/// The resulting bus clocks never exceed the requested ones. Among all valid
+/// configurations, the one closest to the OPI bus clock is chosen first, then
+/// the one closest to the SPI bus clock. Ties go to the lowest XSPI kernel
+/// clock, then the lowest PLL post-divider and highest PLL reference frequency.
+///
+/// Returns `None` if no configuration satisfies the PLL and XSPI limits.
+pub const fn xspi_clocks(hse_hz: u32, spi_bus_hz: u32, opi_bus_hz: u32) -> Option<XspiClocks> {
+    let hse = hse_hz as u64;
+    let spi_target = spi_bus_hz as u64;
+    let opi_target = opi_bus_hz as u64;
+    if spi_target == 0 || opi_target == 0 {
+        return None;
+    }
+
+    // (opi error, spi error, m, n, s, spi div, opi div)
+    let mut best: Option<(u64, u64, u64, u64, u64, u64, u64)> = None;
+
+    // Pick an even OPI division ratio first: this bounds the kernel clock,
+    // which in turn fixes the VCO frequency for each PLL post-divider.
+    let mut opi_div = 2;
+    while opi_div <= XSPI_DIV_MAX && opi_target * opi_div <= XSPI_KERNEL_MAX_HZ {
+        let mut s = 1;
+        while s <= PLL_DIVST_MAX {
+            let vco_target = opi_target * opi_div * s;
+            let mut m = 1;
+            while m <= PLL_PREDIV_MAX {
+                let reference = hse / m;
+                if reference >= PLL_REF_MIN_HZ && reference <= PLL_REF_MAX_HZ {
+                    // Round the multiplier down, so we never overclock.
+                    let n = vco_target * m / hse;
+                    let vco = hse * n / m;
+                    if n >= PLL_MUL_MIN
+                        && n <= PLL_MUL_MAX
+                        && vco >= PLL_VCO_MIN_HZ
+                        && vco <= PLL_VCO_MAX_HZ
+                    {
+                        let kernel = vco / s;
+                        let spi_div = even_div(kernel, spi_target);
+                        if kernel <= XSPI_KERNEL_MAX_HZ && spi_div <= XSPI_DIV_MAX {
+                            let opi_err = opi_target - kernel / opi_div;
+                            let spi_err = spi_target - kernel / spi_div;
+                            let better = match best {
+                                None => true,
+                                Some((best_opi, best_spi, ..)) => {
+                                    opi_err < best_opi
+                                        || (opi_err == best_opi && spi_err < best_spi)
+                                }
+                            };
+                            if better {
+                                best = Some((opi_err, spi_err, m, n, s, spi_div, opi_div));
+                            }
+                        }
+                    }
+                }
+                m += 1;
+            }
+            s += 1;
+        }
+        opi_div += 2;
+    }
+
+    match best {
+        None => None,
+        Some((_, _, m, n, s, spi_div, opi_div)) => {
+            let kernel = hse * n / m / s;
+            Some(XspiClocks {
+                prediv: m as u8,
+                mul: n as u16,
+                divs: s as u8,
+                spi_prescaler: (spi_div - 1) as u8,
+                opi_prescaler: (opi_div - 1) as u8,
+                kernel_hz: kernel as u32,
+                spi_bus_hz: (kernel / spi_div) as u32,
+                opi_bus_hz: (kernel / opi_div) as u32,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that `clocks` respects the PLL and XSPI limits, and does not
+    /// exceed the requested bus clocks.
+    fn check_limits(hse_hz: u32, spi_bus_hz: u32, opi_bus_hz: u32, clocks: XspiClocks) {
+        let hse = hse_hz as u64;
+        let (m, n, s) = (clocks.prediv as u64, clocks.mul as u64, clocks.divs as u64);
+        assert!((1..=PLL_PREDIV_MAX).contains(&m));
+        assert!((PLL_REF_MIN_HZ..=PLL_REF_MAX_HZ).contains(&(hse / m)));
+        assert!((PLL_MUL_MIN..=PLL_MUL_MAX).contains(&n));
+        assert!((PLL_VCO_MIN_HZ..=PLL_VCO_MAX_HZ).contains(&(hse * n / m)));
+        assert!((1..=PLL_DIVST_MAX).contains(&s));
+
+        let kernel = clocks.kernel_hz as u64;
+        assert_eq!(kernel, hse * n / m / s);
+        assert!(kernel <= XSPI_KERNEL_MAX_HZ);
+        for (prescaler, bus, target) in [
+            (clocks.spi_prescaler, clocks.spi_bus_hz, spi_bus_hz),
+            (clocks.opi_prescaler, clocks.opi_bus_hz, opi_bus_hz),
+        ] {
+            let div = prescaler as u64 + 1;
+            assert!(div.is_multiple_of(2), "odd XSPI division ratio {div}");
+            assert_eq!(bus as u64, kernel / div);
+            assert!(bus <= target);
+        }
+    }
+
+    #[test]
+    fn reachable_targets() {
+        // HSE, SPI bus, OPI bus, and the expected kernel and bus clocks.
+        let table = [
+            // The Nucleo board's settings, see `board`.
+            (
+                24_000_000,
+                75_000_000,
+                150_000_000,
+                (300_000_000, 75_000_000, 150_000_000),
+            ),
+            (
+                24_000_000,
+                50_000_000,
+                200_000_000,
+                (400_000_000, 50_000_000, 200_000_000),
+            ),
+            (
+                24_000_000,
+                133_000_000,
+                133_000_000,
+                (266_000_000, 133_000_000, 133_000_000),
+            ),
+            (
+                25_000_000,
+                66_000_000,
+                100_000_000,
+                (200_000_000, 50_000_000, 100_000_000),
+            ),
+            (
+                8_000_000,
+                20_000_000,
+                25_000_000,
+                (200_000_000, 20_000_000, 25_000_000),
+            ),
+        ];
+        for (hse, spi, opi, expected) in table {
+            let clocks = xspi_clocks(hse, spi, opi).unwrap();
+            check_limits(hse, spi, opi, clocks);
+            assert_eq!(
+                (clocks.kernel_hz, clocks.spi_bus_hz, clocks.opi_bus_hz),
+                expected,
+                "HSE {hse}, SPI {spi}, OPI {opi}"
+            );
+        }
+    }
+
+    #[test]
+    fn baseline_is_const() {
+        const CLOCKS: Option<XspiClocks> = xspi_clocks(24_000_000, 75_000_000, 150_000_000);
+        let clocks = CLOCKS.unwrap();
+        assert_eq!((clocks.spi_prescaler, clocks.opi_prescaler), (3, 1));
+    }
+
+    #[test]
+    fn unreachable_targets() {
+        let table = [
+            // No bus clock.
+            (24_000_000, 0, 150_000_000),
+            (24_000_000, 75_000_000, 0),
+            // OPI bus above half the maximum kernel clock.
+            (24_000_000, 75_000_000, 250_000_000),
+            // SPI bus below the kernel clock divided by 256.
+            (24_000_000, 1_000, 150_000_000),
+            // HSE below the minimum PLL reference clock.
+            (500_000, 75_000_000, 150_000_000),
+        ];
+        for (hse, spi, opi) in table {
+            assert_eq!(
+                xspi_clocks(hse, spi, opi),
+                None,
+                "HSE {hse}, SPI {spi}, OPI {opi}"
+            );
+        }
+    }
+}